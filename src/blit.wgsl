@@ -0,0 +1,34 @@
+// Copies one texture into another by drawing a single triangle that covers the whole target.
+// Used to downsample each mip level from the level above it. Always reads the source's first
+// level, as WebGL2 can't bind views of the other levels
+
+struct VertexOutput {
+  @builtin(position) clip_position: vec4<f32>, 
+  @location(0)       texture_coords: vec2<f32>, 
+};
+
+@stage(vertex)
+fn vs_main(@builtin(vertex_index) in_vertex_index: u32) -> VertexOutput {
+  var out: VertexOutput;
+
+  // (0, 0), (2, 0), (0, 2), the parts of the triangle outside the target are clipped
+  let uv = vec2<f32>(f32((in_vertex_index << 1u) & 2u), f32(in_vertex_index & 2u));
+
+  out.clip_position = vec4<f32>(uv * vec2<f32>(2., -2.) + vec2<f32>(-1., 1.), 0., 1.);
+  out.texture_coords = uv;
+
+  return out;
+}
+
+@group(0)
+@binding(0)
+var src_texture : texture_2d<f32>;
+
+@group(0)
+@binding(1)
+var src_sampler : sampler; 
+
+@stage(fragment)
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+  return textureSampleLevel(src_texture, src_sampler, in.texture_coords, 0.); 
+}
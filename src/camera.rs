@@ -1,5 +1,6 @@
 use bytemuck::{Zeroable, Pod};
-use cgmath::{Point3, Vector3, Matrix4, perspective, Deg, SquareMatrix};
+use cgmath::{InnerSpace, Point3, Vector3, Matrix4, perspective, ortho, Deg, Rad, Angle, SquareMatrix};
+use wgpu::CompareFunction;
 
 #[rustfmt::skip]
 pub const OPENGL_TO_WGPU_MATRIX: Matrix4<f32> = Matrix4::new(
@@ -9,6 +10,14 @@ pub const OPENGL_TO_WGPU_MATRIX: Matrix4<f32> = Matrix4::new(
   0.0, 0.0, 0.5, 1.0,
 );
 
+// Flips wgpu depth so that 0 becomes 1 and 1 becomes 0
+#[rustfmt::skip]
+pub const REVERSE_Z_MATRIX: Matrix4<f32> = Matrix4::new(
+  1.0, 0.0,  0.0, 0.0,
+  0.0, 1.0,  0.0, 0.0,
+  0.0, 0.0, -1.0, 0.0,
+  0.0, 0.0,  1.0, 1.0,
+);
 
 pub const TEST_MAT: Matrix4<f32> = Matrix4::new(
   0.5, 0.0, 0.0, 0.0,
@@ -17,14 +26,54 @@ pub const TEST_MAT: Matrix4<f32> = Matrix4::new(
   0.0, 0.0, 0.5, 1.0,
 );
 
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Projection {
+  // Vertical field of view in degrees
+  Perspective { fovy: f32 },
+  // Height of the view volume in world units at a zoom of 1. The width follows from the aspect
+  Orthographic { height: f32 },
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DepthMode {
+  // Depth goes from 0 at the near plane to 1 at the far plane
+  Standard,
+  // Depth goes from 1 at the near plane towards 0 at infinity. Floating point depth is most
+  // precise near 0, which this pairs with the far away geometry that needs it the most.
+  // Perspective cameras ignore zfar in this mode
+  ReverseZ,
+}
+
+impl DepthMode {
+  // Depth comparison the render pipeline must use with this mode
+  pub fn compare(&self) -> CompareFunction {
+    match self {
+      DepthMode::Standard => CompareFunction::Less,
+      DepthMode::ReverseZ => CompareFunction::Greater,
+    }
+  }
+
+  // Value the depth attachment must be cleared to with this mode
+  pub fn clear_value(&self) -> f32 {
+    match self {
+      DepthMode::Standard => 1.,
+      DepthMode::ReverseZ => 0.,
+    }
+  }
+}
+
+#[derive(Copy, Clone, Debug)]
 pub struct Camera {
-  eye: Point3<f32>,
-  target: Point3<f32>,
-  up: Vector3<f32>, 
-  aspect: f32,
-  fovy: f32,
-  znear: f32,
-  zfar: f32,
+  pub eye: Point3<f32>,
+  pub target: Point3<f32>,
+  pub up: Vector3<f32>, 
+  pub aspect: f32,
+  pub projection: Projection,
+  // Magnification of orthographic projections, larger values show less of the scene
+  pub zoom: f32,
+  pub znear: f32,
+  pub zfar: f32,
+  pub depth_mode: DepthMode,
 }
 
 impl Camera {
@@ -34,21 +83,95 @@ impl Camera {
       target: (0., 0., 0.).into(),
       up: Vector3::unit_y(),
       aspect,
-      fovy: 45.,
+      projection: Projection::Perspective { fovy: 45. },
+      zoom: 1.,
       znear: 0.1,
-      zfar: 100.
+      zfar: 100.,
+      depth_mode: DepthMode::Standard
+    }
+  }
+
+  // Keeps the aspect in sync with the render target. Zero sized targets (e.g. a minimized
+  // window) are ignored so we never end up with a NaN aspect
+  pub fn resize(&mut self, width: u32, height: u32) {
+    if width > 0 && height > 0 {
+      self.aspect = width as f32 / height as f32;
+    }
+  }
+
+  // Switches between perspective and orthographic, sizing the orthographic view volume so
+  // that objects at the target keep roughly the same size on screen
+  pub fn toggle_projection(&mut self) {
+    let distance = (self.target - self.eye).magnitude();
+
+    self.zoom = 1.;
+    self.projection = match self.projection {
+      Projection::Perspective { fovy } => {
+        Projection::Orthographic { height: 2. * distance * (fovy.to_radians() / 2.).tan() }
+      }
+      Projection::Orthographic { .. } => Projection::Perspective { fovy: 45. }
+    };
+  }
+
+  // Projection into wgpu clip space. cgmath follows the OpenGL convention of mapping depth
+  // to [-1, 1], so its matrices are remapped to [0, 1] with OPENGL_TO_WGPU_MATRIX
+  pub fn proj_mat(&self) -> Matrix4<f32> {
+    match (self.projection, self.depth_mode) {
+      (Projection::Perspective { fovy }, DepthMode::Standard) => {
+        OPENGL_TO_WGPU_MATRIX * perspective(Deg(fovy), self.aspect, self.znear, self.zfar)
+      }
+      (Projection::Perspective { fovy }, DepthMode::ReverseZ) => {
+        infinite_reverse_perspective(Deg(fovy).into(), self.aspect, self.znear)
+      }
+      (Projection::Orthographic { height }, depth_mode) => {
+        let top = height / 2. / self.zoom;
+        let right = top * self.aspect;
+        let proj = OPENGL_TO_WGPU_MATRIX * ortho(-right, right, -top, top, self.znear, self.zfar);
+
+        // An orthographic view volume can't extend to infinity, so just flip the depth range
+        match depth_mode {
+          DepthMode::Standard => proj,
+          DepthMode::ReverseZ => REVERSE_Z_MATRIX * proj,
+        }
+      }
     }
   }
   
+  // Blends the pose towards other, which provides everything else. Used to render in between
+  // two simulation steps
+  pub fn lerp(&self, other: &Camera, t: f32) -> Camera {
+    Camera {
+      eye: self.eye + (other.eye - self.eye) * t,
+      target: self.target + (other.target - self.target) * t,
+      up: self.up + (other.up - self.up) * t,
+      zoom: self.zoom + (other.zoom - self.zoom) * t,
+      ..*other
+    }
+  }
+
   pub fn vp_mat(&self) -> Matrix4<f32> {
     let view = Matrix4::look_at_rh(self.eye, self.target, self.up);
-    let proj = perspective(Deg(self.fovy), self.aspect, self.znear, self.zfar);
+    let proj = self.proj_mat();
 
     // TEST_MAT
-    OPENGL_TO_WGPU_MATRIX * proj * view
+    proj * view
   }
 }
 
+// Perspective projection with the far plane at infinity, written directly in wgpu clip space.
+// Depth ends up as znear / distance, so 1 at the near plane and approaching 0 far away
+#[rustfmt::skip]
+fn infinite_reverse_perspective(fovy: Rad<f32>, aspect: f32, znear: f32) -> Matrix4<f32> {
+  let f = (fovy / 2.).cot();
+
+  Matrix4::new(
+    f / aspect, 0.0, 0.0,    0.0,
+    0.0,        f,   0.0,    0.0,
+    0.0,        0.0, 0.0,   -1.0,
+    0.0,        0.0, znear,  0.0,
+  )
+}
+
 #[repr(C)]
 #[derive(Debug, Copy, Clone, Pod, Zeroable)]
 pub struct CameraUniform {
@@ -66,3 +189,114 @@ impl CameraUniform {
     self.vp_mat = camera.vp_mat().into(); 
   }
 }
+
+impl Default for CameraUniform {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use cgmath::Vector4;
+
+  #[test]
+  fn resize_updates_vp_mat() {
+    let mut camera = Camera::new(1.);
+
+    camera.resize(800, 400);
+
+    let view = Matrix4::look_at_rh(camera.eye, camera.target, camera.up);
+    let proj = perspective(Deg(45.), 2., 0.1, 100.);
+    let expected = OPENGL_TO_WGPU_MATRIX * proj * view;
+
+    assert_eq!(camera.aspect, 2.);
+    assert_eq!(camera.vp_mat(), expected);
+
+    let mut uniform = CameraUniform::new();
+
+    uniform.update(&camera);
+
+    let expected: [[f32; 4]; 4] = expected.into();
+
+    assert_eq!(uniform.vp_mat, expected);
+  }
+
+  #[test]
+  fn lerp_blends_pose() {
+    let from = Camera::new(1.);
+    let mut to = Camera::new(2.);
+
+    to.eye = (0., 2., 4.).into();
+    to.zoom = 3.;
+
+    let camera = from.lerp(&to, 0.5);
+
+    assert_eq!(camera.eye, Point3::new(0., 2., 3.));
+    assert_eq!(camera.zoom, 2.);
+    assert_eq!(camera.aspect, 2.);
+  }
+
+  #[test]
+  fn resize_ignores_empty_size() {
+    let mut camera = Camera::new(1.5);
+
+    camera.resize(0, 400);
+    camera.resize(800, 0);
+
+    assert_eq!(camera.aspect, 1.5);
+  }
+
+  #[test]
+  fn resize_widens_orthographic_view() {
+    let mut camera = Camera::new(1.);
+
+    camera.eye = (0., 0., 5.).into();
+    camera.projection = Projection::Orthographic { height: 2. };
+    camera.resize(800, 400);
+
+    // With a 2:1 aspect the right edge of the view volume is at x = 2
+    let clip = camera.vp_mat() * Vector4::new(2., 1., 0., 1.);
+
+    assert!((clip.x / clip.w - 1.).abs() < 1e-6);
+    assert!((clip.y / clip.w - 1.).abs() < 1e-6);
+  }
+
+  fn depth(proj: Matrix4<f32>, z: f32) -> f32 {
+    let clip = proj * Vector4::new(0., 0., z, 1.);
+
+    clip.z / clip.w
+  }
+
+  #[test]
+  fn reverse_z_matrix_flips_depth() {
+    let near = REVERSE_Z_MATRIX * Vector4::new(0., 0., 0., 1.);
+    let far = REVERSE_Z_MATRIX * Vector4::new(0., 0., 1., 1.);
+
+    assert_eq!(near.z / near.w, 1.);
+    assert_eq!(far.z / far.w, 0.);
+  }
+
+  #[test]
+  fn infinite_reverse_perspective_maps_near_to_one() {
+    let proj = infinite_reverse_perspective(Deg(45.).into(), 1.5, 0.1);
+
+    assert!((depth(proj, -0.1) - 1.).abs() < 1e-6);
+    assert!((depth(proj, -10.) - 0.01).abs() < 1e-6);
+    assert!(depth(proj, -1e7) < 1e-6);
+  }
+
+  #[test]
+  fn reverse_z_orthographic_maps_near_to_one() {
+    let mut camera = Camera::new(1.);
+
+    camera.projection = Projection::Orthographic { height: 2. };
+    camera.depth_mode = DepthMode::ReverseZ;
+
+    let proj = camera.proj_mat();
+
+    assert!((depth(proj, -camera.znear) - 1.).abs() < 1e-6);
+    assert!(depth(proj, -camera.zfar).abs() < 1e-6);
+  }
+}
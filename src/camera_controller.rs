@@ -0,0 +1,438 @@
+use std::collections::HashMap;
+use std::f32::consts::FRAC_PI_2;
+use std::time::Duration;
+use cgmath::{InnerSpace, Vector2, Vector3, Zero};
+use winit::dpi::PhysicalPosition;
+use winit::event::*;
+
+use crate::camera::{Camera, Projection};
+
+// Keep the pitch just short of the poles, otherwise look_at_rh degenerates when the
+// view direction becomes parallel to the up vector
+const MAX_PITCH: f32 = FRAC_PI_2 - 0.01;
+
+// Controller defaults, also used by RendererConfig
+pub const ORBIT_ROTATE_SPEED: f32 = 0.01;
+pub const ORBIT_ZOOM_SPEED: f32 = 0.1;
+pub const FLY_SPEED: f32 = 2.;
+pub const FLY_SENSITIVITY: f32 = 0.002;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CameraMode {
+  Orbit,
+  Fly
+}
+
+// Holds a controller for each camera mode and forwards input to the active one. Switching
+// modes keeps the camera where it is so the new controller picks up from the current
+// eye/target, and keeps each controller's settings
+pub struct CameraController {
+  pub orbit: OrbitController,
+  pub fly: FlyController,
+  mode: CameraMode,
+}
+
+impl CameraController {
+  pub fn new(mode: CameraMode) -> Self {
+    Self { orbit: OrbitController::new(), fly: FlyController::new(), mode }
+  }
+
+  pub fn mode(&self) -> CameraMode {
+    self.mode
+  }
+
+  // Input still pending for the controller we switch away from is dropped, e.g. a key held
+  // down while toggling
+  pub fn toggle(&mut self) {
+    self.mode = match self.mode {
+      CameraMode::Orbit => {
+        self.orbit.reset();
+        CameraMode::Fly
+      }
+      CameraMode::Fly => {
+        self.fly.reset();
+        CameraMode::Orbit
+      }
+    }
+  }
+
+  pub fn process_event(&mut self, event: &WindowEvent) -> bool {
+    match self.mode {
+      CameraMode::Orbit => self.orbit.process_event(event),
+      CameraMode::Fly => self.fly.process_event(event),
+    }
+  }
+
+  // Raw mouse motion, delivered while the pointer is locked
+  pub fn process_mouse_motion(&mut self, dx: f64, dy: f64) {
+    if let CameraMode::Fly = self.mode {
+      self.fly.process_mouse_motion(dx, dy);
+    }
+  }
+
+  pub fn update_camera(&mut self, camera: &mut Camera, dt: Duration) {
+    match self.mode {
+      CameraMode::Orbit => self.orbit.update_camera(camera),
+      CameraMode::Fly => self.fly.update_camera(camera, dt),
+    }
+  }
+}
+
+// Orbits the camera eye around its target. Input events are accumulated as they arrive
+// and then applied to the camera once per frame in `update_camera`
+pub struct OrbitController {
+  // Radians per pixel of drag
+  pub rotate_speed: f32,
+  // Fraction of the distance to the target per wheel notch
+  pub zoom_speed: f32,
+  pub min_radius: f32,
+  pub max_radius: f32,
+
+  rotate_delta: Vector2<f32>,
+  zoom_delta: f32,
+  is_dragging: bool,
+  cursor: Option<PhysicalPosition<f64>>,
+  touches: HashMap<u64, PhysicalPosition<f64>>,
+}
+
+impl OrbitController {
+  pub fn new() -> Self {
+    Self {
+      rotate_speed: ORBIT_ROTATE_SPEED,
+      zoom_speed: ORBIT_ZOOM_SPEED,
+      min_radius: 0.1,
+      max_radius: 1000.,
+      rotate_delta: Vector2::zero(),
+      zoom_delta: 0.,
+      is_dragging: false,
+      cursor: None,
+      touches: HashMap::new(),
+    }
+  }
+
+  // Drops accumulated input, keeping the settings
+  pub fn reset(&mut self) {
+    *self = Self {
+      rotate_speed: self.rotate_speed,
+      zoom_speed: self.zoom_speed,
+      min_radius: self.min_radius,
+      max_radius: self.max_radius,
+      ..Self::new()
+    };
+  }
+
+  // Returns true if the event was consumed by the controller
+  pub fn process_event(&mut self, event: &WindowEvent) -> bool {
+    match event {
+      WindowEvent::MouseInput { state, button: MouseButton::Left, .. } => {
+        self.is_dragging = *state == ElementState::Pressed;
+        true
+      }
+      WindowEvent::CursorMoved { position, .. } => {
+        if let (true, Some(last)) = (self.is_dragging, self.cursor) {
+          self.rotate_delta.x += (position.x - last.x) as f32;
+          self.rotate_delta.y += (position.y - last.y) as f32;
+        }
+        self.cursor = Some(*position);
+        self.is_dragging
+      }
+      WindowEvent::MouseWheel { delta, .. } => {
+        self.zoom_delta += match delta {
+          MouseScrollDelta::LineDelta(_, y) => *y,
+          // Browsers report roughly 100 pixels per wheel notch
+          MouseScrollDelta::PixelDelta(position) => position.y as f32 / 100.,
+        };
+        true
+      }
+      WindowEvent::Touch(touch) => {
+        self.process_touch(touch);
+        true
+      }
+      _ => false
+    }
+  }
+
+  // One finger rotates, two fingers pinch to zoom
+  fn process_touch(&mut self, touch: &Touch) {
+    match touch.phase {
+      TouchPhase::Started => {
+        self.touches.insert(touch.id, touch.location);
+      }
+      TouchPhase::Moved => {
+        let last = match self.touches.get(&touch.id) {
+          Some(last) => *last,
+          None => return
+        };
+
+        match self.touches.len() {
+          1 => {
+            self.rotate_delta.x += (touch.location.x - last.x) as f32;
+            self.rotate_delta.y += (touch.location.y - last.y) as f32;
+          }
+          2 => {
+            let other = self.touches.iter()
+              .find(|(id, _)| **id != touch.id)
+              .map(|(_, location)| *location)
+              .expect("Second touch");
+            let before = distance(last, other);
+            let after = distance(touch.location, other);
+
+            if before > 0. && after > 0. {
+              // Spreading the fingers apart zooms in, matching the wheel direction
+              self.zoom_delta += (after / before).ln() / self.zoom_speed;
+            }
+          }
+          _ => {}
+        }
+
+        self.touches.insert(touch.id, touch.location);
+      }
+      TouchPhase::Ended | TouchPhase::Cancelled => {
+        self.touches.remove(&touch.id);
+      }
+    }
+  }
+
+  // Applies any input accumulated since the last call to the camera
+  pub fn update_camera(&mut self, camera: &mut Camera) {
+    let offset = camera.eye - camera.target;
+    let radius = offset.magnitude();
+
+    if radius == 0. {
+      return;
+    }
+
+    let yaw = offset.x.atan2(offset.z) - self.rotate_delta.x * self.rotate_speed;
+    let pitch = ((offset.y / radius).asin() + self.rotate_delta.y * self.rotate_speed)
+      .clamp(-MAX_PITCH, MAX_PITCH);
+    let scale = (-self.zoom_delta * self.zoom_speed).exp();
+
+    // Moving closer has no visible effect with an orthographic projection, so zoom instead
+    let radius = match camera.projection {
+      Projection::Perspective { .. } => (radius * scale).clamp(self.min_radius, self.max_radius),
+      Projection::Orthographic { .. } => {
+        camera.zoom /= scale;
+        radius
+      }
+    };
+
+    camera.eye = camera.target + radius * Vector3::new(
+      pitch.cos() * yaw.sin(),
+      pitch.sin(),
+      pitch.cos() * yaw.cos()
+    );
+
+    self.rotate_delta = Vector2::zero();
+    self.zoom_delta = 0.;
+  }
+}
+
+fn distance(a: PhysicalPosition<f64>, b: PhysicalPosition<f64>) -> f32 {
+  ((a.x - b.x).powi(2) + (a.y - b.y).powi(2)).sqrt() as f32
+}
+
+// First person camera. WASD moves along the view direction, Space/Shift move up and down,
+// and mouse motion (with the pointer locked) turns the view
+pub struct FlyController {
+  // Units per second
+  pub speed: f32,
+  // Radians per pixel of mouse motion
+  pub sensitivity: f32,
+
+  forward: f32,
+  backward: f32,
+  left: f32,
+  right: f32,
+  up: f32,
+  down: f32,
+  look_delta: Vector2<f32>,
+}
+
+impl FlyController {
+  pub fn new() -> Self {
+    Self {
+      speed: FLY_SPEED,
+      sensitivity: FLY_SENSITIVITY,
+      forward: 0.,
+      backward: 0.,
+      left: 0.,
+      right: 0.,
+      up: 0.,
+      down: 0.,
+      look_delta: Vector2::zero(),
+    }
+  }
+
+  // Drops accumulated input, keeping the settings
+  pub fn reset(&mut self) {
+    *self = Self { speed: self.speed, sensitivity: self.sensitivity, ..Self::new() };
+  }
+
+  pub fn process_event(&mut self, event: &WindowEvent) -> bool {
+    let (keycode, state) = match event {
+      WindowEvent::KeyboardInput {
+        input: KeyboardInput { virtual_keycode: Some(keycode), state, .. }, ..
+      } => (keycode, state),
+      _ => return false
+    };
+
+    let amount = if *state == ElementState::Pressed { 1. } else { 0. };
+
+    match keycode {
+      VirtualKeyCode::W | VirtualKeyCode::Up => self.forward = amount,
+      VirtualKeyCode::S | VirtualKeyCode::Down => self.backward = amount,
+      VirtualKeyCode::A | VirtualKeyCode::Left => self.left = amount,
+      VirtualKeyCode::D | VirtualKeyCode::Right => self.right = amount,
+      VirtualKeyCode::Space => self.up = amount,
+      VirtualKeyCode::LShift => self.down = amount,
+      _ => return false
+    }
+
+    true
+  }
+
+  pub fn process_mouse_motion(&mut self, dx: f64, dy: f64) {
+    self.look_delta.x += dx as f32;
+    self.look_delta.y += dy as f32;
+  }
+
+  // Moves the eye and target together so the distance between them is preserved, which
+  // lets the orbit controller resume around the point we were looking at
+  pub fn update_camera(&mut self, camera: &mut Camera, dt: Duration) {
+    let offset = camera.target - camera.eye;
+    let distance = offset.magnitude();
+
+    if distance == 0. {
+      return;
+    }
+
+    let dir = offset / distance;
+    let yaw = dir.x.atan2(dir.z) - self.look_delta.x * self.sensitivity;
+    let pitch = (dir.y.asin() - self.look_delta.y * self.sensitivity).clamp(-MAX_PITCH, MAX_PITCH);
+    let forward = Vector3::new(pitch.cos() * yaw.sin(), pitch.sin(), pitch.cos() * yaw.cos());
+    let right = forward.cross(camera.up).normalize();
+
+    let step = self.speed * dt.as_secs_f32();
+    let movement = forward * (self.forward - self.backward)
+      + right * (self.right - self.left)
+      + camera.up * (self.up - self.down);
+
+    camera.eye += movement * step;
+    camera.target = camera.eye + forward * distance;
+
+    self.look_delta = Vector2::zero();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use cgmath::{MetricSpace, Point3};
+
+  fn key(keycode: VirtualKeyCode, state: ElementState) -> WindowEvent<'static> {
+    #[allow(deprecated)]
+    WindowEvent::KeyboardInput {
+      device_id: unsafe { DeviceId::dummy() },
+      input: KeyboardInput { scancode: 0, state, virtual_keycode: Some(keycode), modifiers: ModifiersState::empty() },
+      is_synthetic: false,
+    }
+  }
+
+  fn camera() -> Camera {
+    let mut camera = Camera::new(1.);
+
+    camera.eye = Point3::new(0., 0., 5.);
+    camera.target = Point3::new(0., 0., 0.);
+    camera
+  }
+
+  fn assert_near(a: Point3<f32>, b: Point3<f32>) {
+    assert!(a.distance(b) < 1e-4, "{:?} != {:?}", a, b);
+  }
+
+  #[test]
+  fn fly_moves_at_configured_speed() {
+    let mut controller = FlyController::new();
+    let mut camera = camera();
+
+    controller.speed = 3.;
+    assert!(controller.process_event(&key(VirtualKeyCode::W, ElementState::Pressed)));
+    controller.update_camera(&mut camera, Duration::from_millis(500));
+
+    assert_near(camera.eye, Point3::new(0., 0., 3.5));
+    assert_near(camera.target, Point3::new(0., 0., -1.5));
+
+    // Released keys stop the camera
+    controller.process_event(&key(VirtualKeyCode::W, ElementState::Released));
+    controller.update_camera(&mut camera, Duration::from_secs(1));
+
+    assert_near(camera.eye, Point3::new(0., 0., 3.5));
+  }
+
+  #[test]
+  fn fly_turns_by_sensitivity_and_clamps_pitch() {
+    let mut controller = FlyController::new();
+    let mut camera = camera();
+
+    controller.sensitivity = 0.01;
+    controller.process_mouse_motion(-std::f64::consts::FRAC_PI_2 * 100., 0.);
+    controller.update_camera(&mut camera, Duration::ZERO);
+
+    // Looking down -z, turning left by a quarter turn looks down -x
+    assert_near(camera.eye, Point3::new(0., 0., 5.));
+    assert_near(camera.target, Point3::new(-5., 0., 5.));
+
+    controller.process_mouse_motion(0., -1000.);
+    controller.update_camera(&mut camera, Duration::ZERO);
+
+    let dir = (camera.target - camera.eye).normalize();
+
+    assert!((dir.y.asin() - MAX_PITCH).abs() < 1e-4);
+  }
+
+  #[test]
+  fn orbit_zooms_within_limits() {
+    let mut controller = OrbitController::new();
+    let mut camera = camera();
+
+    controller.min_radius = 2.;
+    controller.process_event(&WindowEvent::MouseWheel {
+      device_id: unsafe { DeviceId::dummy() },
+      delta: MouseScrollDelta::LineDelta(0., 100.),
+      phase: TouchPhase::Moved,
+      #[allow(deprecated)]
+      modifiers: ModifiersState::empty(),
+    });
+    controller.update_camera(&mut camera);
+
+    assert_near(camera.eye, Point3::new(0., 0., 2.));
+  }
+
+  #[test]
+  fn orbit_rotation_keeps_distance() {
+    let mut controller = OrbitController::new();
+    let mut camera = camera();
+
+    controller.rotate_delta = Vector2::new(-std::f32::consts::FRAC_PI_2 / controller.rotate_speed, 0.);
+    controller.update_camera(&mut camera);
+
+    assert_near(camera.eye, Point3::new(5., 0., 0.));
+    assert_eq!(camera.target, Point3::new(0., 0., 0.));
+  }
+
+  #[test]
+  fn toggle_keeps_settings_and_drops_input() {
+    let mut controller = CameraController::new(CameraMode::Fly);
+    let mut camera = camera();
+
+    controller.fly.speed = 10.;
+    controller.process_event(&key(VirtualKeyCode::W, ElementState::Pressed));
+    controller.toggle();
+    controller.toggle();
+    controller.update_camera(&mut camera, Duration::from_secs(1));
+
+    assert_eq!(controller.mode(), CameraMode::Fly);
+    assert_eq!(controller.fly.speed, 10.);
+    assert_near(camera.eye, Point3::new(0., 0., 5.));
+  }
+}
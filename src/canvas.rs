@@ -0,0 +1,136 @@
+use std::cell::Cell;
+use std::error::Error;
+use std::rc::Rc;
+use wasm_bindgen::JsCast;
+use wasm_bindgen::closure::Closure;
+use web_sys::{HtmlCanvasElement, ResizeObserver, ResizeObserverEntry};
+
+// Where the renderer draws on the page
+pub enum Mount {
+  // A new 450x400 canvas appended to the document body
+  Body,
+  // An existing canvas, looked up by its id
+  CanvasId(String),
+  Canvas(HtmlCanvasElement),
+}
+
+impl Mount {
+  // The existing canvas to attach to, if any
+  pub fn canvas(self) -> Result<Option<HtmlCanvasElement>, Box<dyn Error>> {
+    match self {
+      Mount::Body => Ok(None),
+      Mount::Canvas(canvas) => Ok(Some(canvas)),
+      Mount::CanvasId(id) => {
+        let element = web_sys::window()
+          .and_then(|window| window.document())
+          .and_then(|document| document.get_element_by_id(&id))
+          .ok_or_else(|| format!("No element with id {}", id))?;
+
+        let canvas = element.dyn_into::<HtmlCanvasElement>()
+          .map_err(|_| format!("Element {} is not a canvas", id))?;
+
+        Ok(Some(canvas))
+      }
+    }
+  }
+}
+
+// From JS, a string is taken as a canvas id
+impl From<wasm_bindgen::JsValue> for Mount {
+  fn from(value: wasm_bindgen::JsValue) -> Self {
+    match value.as_string() {
+      Some(id) => Mount::CanvasId(id),
+      None => Mount::Canvas(value.unchecked_into()),
+    }
+  }
+}
+
+// Runs f, e.g. building the winit window, and puts back the canvas' inline style and size
+// afterwards. winit gives canvases a default size, which would override the page's layout
+pub fn preserving_layout<T>(canvas: &HtmlCanvasElement, f: impl FnOnce() -> T) -> T {
+  let style = canvas.get_attribute("style");
+  let (width, height) = (canvas.width(), canvas.height());
+  let result = f();
+
+  match style {
+    Some(style) => canvas.set_attribute("style", &style).ok(),
+    None => canvas.remove_attribute("style").ok(),
+  };
+
+  canvas.set_width(width);
+  canvas.set_height(height);
+  result
+}
+
+// Keeps the canvas' drawing buffer the size of its CSS box, in physical pixels. Only the
+// buffer is touched, the event loop notices the new size through `Window::inner_size`
+pub struct CanvasResizer {
+  observer: ResizeObserver,
+  _callback: Closure<dyn FnMut(js_sys::Array)>,
+}
+
+impl CanvasResizer {
+  pub fn new(canvas: &HtmlCanvasElement) -> Result<Self, Box<dyn Error>> {
+    let rect = canvas.get_bounding_client_rect();
+    // CSS size the buffer was last fitted to, while waiting for the observer to report on it
+    let fitted = Rc::new(Cell::new(None));
+
+    if fit_buffer(canvas, rect.width(), rect.height()) {
+      fitted.set(Some((rect.width(), rect.height())));
+    }
+
+    let target = canvas.clone();
+    let callback = Closure::wrap(Box::new(move |entries: js_sys::Array| {
+      for entry in entries.iter() {
+        let rect = entry.unchecked_into::<ResizeObserverEntry>().content_rect();
+        let (width, height) = (rect.width(), rect.height());
+
+        // A canvas without a CSS size is laid out at its buffer size, so growing the buffer by
+        // the device pixel ratio grows the box, which would grow the buffer again. Pin such a
+        // canvas to the size it had instead
+        if let Some((fitted_width, fitted_height)) = fitted.take() {
+          if width == target.width() as f64 && height == target.height() as f64 && (width, height) != (fitted_width, fitted_height) {
+            let style = target.style();
+
+            style.set_property("width", &format!("{}px", fitted_width)).ok();
+            style.set_property("height", &format!("{}px", fitted_height)).ok();
+            continue;
+          }
+        }
+
+        if fit_buffer(&target, width, height) {
+          fitted.set(Some((width, height)));
+        }
+      }
+    }) as Box<dyn FnMut(js_sys::Array)>);
+
+    let observer = ResizeObserver::new(callback.as_ref().unchecked_ref())
+      .map_err(|_| "Unable to create ResizeObserver")?;
+
+    observer.observe(canvas);
+
+    Ok(Self { observer, _callback: callback })
+  }
+}
+
+impl Drop for CanvasResizer {
+  fn drop(&mut self) {
+    self.observer.disconnect();
+  }
+}
+
+// Sizes are in CSS pixels. An element that isn't laid out (e.g. display: none) measures 0x0,
+// keep the last size rather than configuring an empty surface. Returns whether the buffer changed
+fn fit_buffer(canvas: &HtmlCanvasElement, css_width: f64, css_height: f64) -> bool {
+  let scale_factor = web_sys::window().map(|window| window.device_pixel_ratio()).unwrap_or(1.);
+  let width = (css_width * scale_factor).round() as u32;
+  let height = (css_height * scale_factor).round() as u32;
+
+  if width == 0 || height == 0 || (width == canvas.width() && height == canvas.height()) {
+    return false;
+  }
+
+  canvas.set_width(width);
+  canvas.set_height(height);
+  true
+}
@@ -0,0 +1,163 @@
+use std::time::Duration;
+use bytemuck::{Pod, Zeroable};
+
+// Time since the page was loaded
+#[cfg(target_arch = "wasm32")]
+pub fn now() -> Duration {
+  let ms = web_sys::window()
+    .and_then(|window| window.performance())
+    .map(|performance| performance.now())
+    .unwrap_or(0.);
+
+  Duration::from_secs_f64(ms.max(0.) / 1000.)
+}
+
+// Time since the clock was first read
+#[cfg(not(target_arch = "wasm32"))]
+pub fn now() -> Duration {
+  use std::sync::OnceLock;
+  use std::time::Instant;
+
+  static ORIGIN: OnceLock<Instant> = OnceLock::new();
+
+  ORIGIN.get_or_init(Instant::now).elapsed()
+}
+
+// What happened since the previous tick
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct FrameTime {
+  // Since the clock was created
+  pub elapsed: Duration,
+  // Since the previous tick
+  pub delta: Duration,
+  // Number of fixed steps to simulate this frame
+  pub steps: u32,
+  // How far we are between the last simulated step and the next, in [0, 1]. Rendering blends
+  // the previous and current state by this amount so motion stays smooth at any frame rate.
+  // Only reaches 1 when time was dropped after a stall, a full step is then still pending
+  pub alpha: f32,
+  pub frame: u32,
+}
+
+// Splits real time into fixed simulation steps, so updates behave the same regardless of
+// the display's refresh rate
+pub struct FrameClock {
+  pub step: Duration,
+  // After a long stall (e.g. a background tab) we'd rather drop time than simulate a burst of
+  // steps to catch up
+  pub max_steps: u32,
+  start: Duration,
+  last: Duration,
+  accumulator: Duration,
+  frame: u32,
+}
+
+impl FrameClock {
+  pub fn new(step: Duration) -> Self {
+    Self::starting_at(step, now())
+  }
+
+  fn starting_at(step: Duration, start: Duration) -> Self {
+    Self { step, max_steps: 8, start, last: start, accumulator: Duration::ZERO, frame: 0 }
+  }
+
+  pub fn tick(&mut self) -> FrameTime {
+    self.tick_at(now())
+  }
+
+  fn tick_at(&mut self, now: Duration) -> FrameTime {
+    let delta = now.saturating_sub(self.last);
+    let mut steps = 0;
+
+    self.last = now;
+    self.accumulator += delta;
+
+    while self.accumulator >= self.step && steps < self.max_steps {
+      self.accumulator -= self.step;
+      steps += 1;
+    }
+
+    if steps == self.max_steps {
+      self.accumulator = self.accumulator.min(self.step);
+    }
+
+    self.frame = self.frame.wrapping_add(1);
+
+    FrameTime {
+      elapsed: now.saturating_sub(self.start),
+      delta,
+      steps,
+      alpha: (self.accumulator.as_secs_f32() / self.step.as_secs_f32()).min(1.),
+      frame: self.frame,
+    }
+  }
+}
+
+// Frame timing for shaders, bound to group 3
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, Pod, Zeroable)]
+pub struct FrameUniform {
+  // Seconds
+  pub elapsed: f32,
+  pub delta: f32,
+  pub alpha: f32,
+  pub frame: u32,
+}
+
+impl FrameUniform {
+  pub fn update(&mut self, time: &FrameTime) {
+    self.elapsed = time.elapsed.as_secs_f32();
+    self.delta = time.delta.as_secs_f32();
+    self.alpha = time.alpha;
+    self.frame = time.frame;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const STEP: Duration = Duration::from_millis(10);
+
+  #[test]
+  fn accumulates_partial_steps() {
+    let mut clock = FrameClock::starting_at(STEP, Duration::ZERO);
+
+    let time = clock.tick_at(Duration::from_millis(15));
+
+    assert_eq!(time.steps, 1);
+    assert!((time.alpha - 0.5).abs() < 1e-6);
+
+    // The leftover 5ms carries over into the next frame
+    let time = clock.tick_at(Duration::from_millis(20));
+
+    assert_eq!(time.steps, 1);
+    assert_eq!(time.delta, Duration::from_millis(5));
+    assert_eq!(time.elapsed, Duration::from_millis(20));
+    assert_eq!(time.alpha, 0.);
+    assert_eq!(time.frame, 2);
+  }
+
+  #[test]
+  fn drops_time_after_a_stall() {
+    let mut clock = FrameClock::starting_at(STEP, Duration::ZERO);
+
+    let time = clock.tick_at(Duration::from_secs(10));
+
+    assert_eq!(time.steps, clock.max_steps);
+    assert_eq!(time.alpha, 1.);
+
+    let time = clock.tick_at(Duration::from_secs(10) + Duration::from_millis(1));
+
+    assert!(time.steps <= 1);
+  }
+
+  #[test]
+  fn ignores_time_going_backwards() {
+    let mut clock = FrameClock::starting_at(STEP, Duration::from_millis(50));
+
+    let time = clock.tick_at(Duration::from_millis(40));
+
+    assert_eq!((time.delta, time.steps, time.elapsed), (Duration::ZERO, 0, Duration::ZERO));
+  }
+}
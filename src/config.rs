@@ -0,0 +1,262 @@
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+use log::LevelFilter;
+use wgpu::{Color, Limits, PowerPreference, PresentMode};
+
+use crate::camera::DepthMode;
+use crate::camera_controller;
+
+#[derive(Debug, PartialEq)]
+pub enum ConfigError {
+  UnknownKey(String),
+  InvalidValue { key: String, value: String },
+}
+
+impl fmt::Display for ConfigError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      ConfigError::UnknownKey(key) => write!(f, "Unknown renderer option {}", key),
+      ConfigError::InvalidValue { key, value } => write!(f, "Invalid value {:?} for renderer option {}", value, key),
+    }
+  }
+}
+
+impl Error for ConfigError {}
+
+// Settings the renderer is created with. Options can also be given as key/value strings, e.g.
+// `?clearColor=0,0,0&presentMode=immediate&logLevel=debug` in the page's url, see RendererConfig::set
+#[derive(Clone, Debug)]
+pub struct RendererConfig {
+  pub clear_color: Color,
+  // Initial size of the window, or of the canvas appended to the page. Mounted canvases are
+  // sized by their CSS instead
+  pub width: u32,
+  pub height: u32,
+  pub present_mode: PresentMode,
+  pub power_preference: PowerPreference,
+  // Requested from the device, the defaults are supported everywhere including WebGL2
+  pub limits: Limits,
+  // The first renderer on a page sets the level for all of them. Natively, RUST_LOG takes precedence
+  pub log_level: LevelFilter,
+  // Depth range the camera starts with. Cameras imported from glTF bring their own
+  pub depth_mode: DepthMode,
+  // Orbit camera, radians per pixel of drag and fraction of the distance per wheel notch
+  pub orbit_rotate_speed: f32,
+  pub orbit_zoom_speed: f32,
+  // Fly camera, units per second and radians per pixel of mouse motion
+  pub fly_speed: f32,
+  pub fly_sensitivity: f32,
+}
+
+impl Default for RendererConfig {
+  fn default() -> Self {
+    Self {
+      clear_color: Color { r: 1., g: 0., b: 0., a: 1. },
+      width: 450,
+      height: 400,
+      present_mode: PresentMode::Fifo,
+      power_preference: PowerPreference::HighPerformance,
+      limits: Limits::downlevel_webgl2_defaults(),
+      log_level: LevelFilter::Warn,
+      depth_mode: DepthMode::Standard,
+      orbit_rotate_speed: camera_controller::ORBIT_ROTATE_SPEED,
+      orbit_zoom_speed: camera_controller::ORBIT_ZOOM_SPEED,
+      fly_speed: camera_controller::FLY_SPEED,
+      fly_sensitivity: camera_controller::FLY_SENSITIVITY,
+    }
+  }
+}
+
+impl RendererConfig {
+  // Reads options from a url query string, with or without the leading `?`. Keys that aren't
+  // renderer options are ignored, as the page may use them for its own purposes
+  pub fn from_query(query: &str) -> Result<Self, ConfigError> {
+    let mut config = Self::default();
+    let pairs = query.trim_start_matches('?').split('&').filter(|pair| !pair.is_empty());
+
+    for pair in pairs {
+      let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+
+      match config.set(key, value) {
+        Ok(()) | Err(ConfigError::UnknownKey(_)) => {}
+        Err(e) => return Err(e),
+      }
+    }
+
+    Ok(config)
+  }
+
+  // Sets a single option from its string form. Keys are camelCased as in JS
+  //   clearColor       r,g,b or r,g,b,a in [0, 1]
+  //   width, height    pixels
+  //   presentMode      fifo, mailbox or immediate
+  //   powerPreference  low-power or high-performance
+  //   limits           webgl2, downlevel or default
+  //   logLevel         off, error, warn, info, debug or trace
+  //   depthMode        standard or reverse-z
+  //   orbitRotateSpeed, orbitZoomSpeed, flySpeed, flySensitivity
+  //                    positive numbers, see the fields
+  pub fn set(&mut self, key: &str, value: &str) -> Result<(), ConfigError> {
+    let invalid = || ConfigError::InvalidValue { key: key.to_string(), value: value.to_string() };
+
+    match key {
+      "clearColor" => self.clear_color = parse_color(value).ok_or_else(invalid)?,
+      "width" => self.width = parse_size(value).ok_or_else(invalid)?,
+      "height" => self.height = parse_size(value).ok_or_else(invalid)?,
+      "presentMode" => {
+        self.present_mode = match value {
+          "fifo" => PresentMode::Fifo,
+          "mailbox" => PresentMode::Mailbox,
+          "immediate" => PresentMode::Immediate,
+          _ => return Err(invalid()),
+        }
+      }
+      "powerPreference" => {
+        self.power_preference = match value {
+          "low-power" => PowerPreference::LowPower,
+          "high-performance" => PowerPreference::HighPerformance,
+          _ => return Err(invalid()),
+        }
+      }
+      "limits" => {
+        self.limits = match value {
+          "webgl2" => Limits::downlevel_webgl2_defaults(),
+          "downlevel" => Limits::downlevel_defaults(),
+          "default" => Limits::default(),
+          _ => return Err(invalid()),
+        }
+      }
+      "logLevel" => self.log_level = LevelFilter::from_str(value).map_err(|_| invalid())?,
+      "depthMode" => {
+        self.depth_mode = match value {
+          "standard" => DepthMode::Standard,
+          "reverse-z" => DepthMode::ReverseZ,
+          _ => return Err(invalid()),
+        }
+      }
+      "orbitRotateSpeed" => self.orbit_rotate_speed = parse_speed(value).ok_or_else(invalid)?,
+      "orbitZoomSpeed" => self.orbit_zoom_speed = parse_speed(value).ok_or_else(invalid)?,
+      "flySpeed" => self.fly_speed = parse_speed(value).ok_or_else(invalid)?,
+      "flySensitivity" => self.fly_sensitivity = parse_speed(value).ok_or_else(invalid)?,
+      _ => return Err(ConfigError::UnknownKey(key.to_string())),
+    }
+
+    Ok(())
+  }
+}
+
+fn parse_color(value: &str) -> Option<Color> {
+  let components = value.split(',')
+    .map(|component| component.trim().parse::<f64>().ok())
+    .collect::<Option<Vec<_>>>()?;
+
+  match components[..] {
+    [r, g, b] => Some(Color { r, g, b, a: 1. }),
+    [r, g, b, a] => Some(Color { r, g, b, a }),
+    _ => None,
+  }
+}
+
+fn parse_size(value: &str) -> Option<u32> {
+  value.parse().ok().filter(|&size| size > 0)
+}
+
+fn parse_speed(value: &str) -> Option<f32> {
+  value.parse().ok().filter(|speed: &f32| speed.is_finite() && *speed > 0.)
+}
+
+#[cfg(target_arch = "wasm32")]
+mod js {
+  use js_sys::{Array, Object};
+  use wasm_bindgen::prelude::*;
+  use wasm_bindgen::JsCast;
+
+  use super::RendererConfig;
+
+  #[wasm_bindgen(typescript_custom_section)]
+  const TS_TYPES: &'static str = r#"
+export interface RendererConfigInit {
+  clearColor?: [number, number, number] | [number, number, number, number];
+  width?: number;
+  height?: number;
+  presentMode?: "fifo" | "mailbox" | "immediate";
+  powerPreference?: "low-power" | "high-performance";
+  limits?: "webgl2" | "downlevel" | "default";
+  logLevel?: "off" | "error" | "warn" | "info" | "debug" | "trace";
+  depthMode?: "standard" | "reverse-z";
+  orbitRotateSpeed?: number;
+  orbitZoomSpeed?: number;
+  flySpeed?: number;
+  flySensitivity?: number;
+}
+"#;
+
+  #[wasm_bindgen]
+  extern "C" {
+    #[wasm_bindgen(typescript_type = "RendererConfigInit | undefined")]
+    pub type RendererConfigInit;
+  }
+
+  impl RendererConfig {
+    // Reads options from a JS object, e.g. `{ clearColor: [0, 0, 0], logLevel: "debug" }`.
+    // Undefined or null give the defaults
+    pub fn from_js(value: &JsValue) -> Result<Self, JsValue> {
+      let mut config = Self::default();
+
+      if value.is_undefined() || value.is_null() {
+        return Ok(config);
+      }
+
+      let object = value.dyn_ref::<Object>().ok_or("Renderer config must be an object")?;
+
+      for entry in Object::entries(object).iter() {
+        let entry = Array::from(&entry);
+        let key = entry.get(0).as_string().unwrap_or_default();
+        let value = entry.get(1);
+        let value = if Array::is_array(&value) {
+          String::from(Array::from(&value).join(","))
+        } else if let Some(number) = value.as_f64() {
+          number.to_string()
+        } else {
+          value.as_string().unwrap_or_default()
+        };
+
+        config.set(&key, &value).map_err(|e| js_sys::Error::new(&e.to_string()))?;
+      }
+
+      Ok(config)
+    }
+  }
+}
+
+#[cfg(target_arch = "wasm32")]
+pub use js::RendererConfigInit;
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn reads_query_string() {
+    let config = RendererConfig::from_query("?clearColor=0,0.5,1&width=800&presentMode=immediate&logLevel=debug&depthMode=reverse-z&flySpeed=5&tab=2").unwrap();
+
+    assert_eq!(config.clear_color, Color { r: 0., g: 0.5, b: 1., a: 1. });
+    assert_eq!((config.width, config.height), (800, 400));
+    assert_eq!(config.present_mode, PresentMode::Immediate);
+    assert_eq!(config.log_level, LevelFilter::Debug);
+    assert_eq!(config.depth_mode, DepthMode::ReverseZ);
+    assert_eq!(config.fly_speed, 5.);
+  }
+
+  #[test]
+  fn rejects_invalid_values() {
+    let invalid = |key: &str, value: &str| Err(ConfigError::InvalidValue { key: key.into(), value: value.into() });
+
+    assert_eq!(RendererConfig::from_query("clearColor=1,0").map(|_| ()), invalid("clearColor", "1,0"));
+    assert_eq!(RendererConfig::from_query("height=0").map(|_| ()), invalid("height", "0"));
+    assert_eq!(RendererConfig::default().set("limits", "huge"), invalid("limits", "huge"));
+    assert_eq!(RendererConfig::default().set("flySensitivity", "-1"), invalid("flySensitivity", "-1"));
+    assert_eq!(RendererConfig::default().set("size", "1"), Err(ConfigError::UnknownKey("size".into())));
+  }
+}
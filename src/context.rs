@@ -0,0 +1,390 @@
+use std::cell::RefCell;
+use std::error::Error;
+use std::fmt;
+use std::rc::{Rc, Weak};
+use wgpu::*;
+use winit::window::Window;
+
+use crate::config::RendererConfig;
+use crate::request_device;
+use crate::texture_resource::TextureError;
+
+// Backends to request an adapter from, in order. On the web wgpu supports either WebGPU or
+// WebGL2 depending on the `webgl` feature, so falling back from one to the other takes a
+// second build of the page. The default page switches to it, see `run` in lib.rs
+#[cfg(not(target_arch = "wasm32"))]
+const BUILD: &str = "native";
+#[cfg(not(target_arch = "wasm32"))]
+const BACKENDS: &[Backends] = &[Backends::PRIMARY, Backends::GL];
+
+#[cfg(all(target_arch = "wasm32", not(feature = "webgl")))]
+const BUILD: &str = "webgpu";
+#[cfg(all(target_arch = "wasm32", not(feature = "webgl")))]
+const BACKENDS: &[Backends] = &[Backends::BROWSER_WEBGPU];
+
+#[cfg(all(target_arch = "wasm32", feature = "webgl"))]
+const BUILD: &str = "webgl2";
+#[cfg(all(target_arch = "wasm32", feature = "webgl"))]
+const BACKENDS: &[Backends] = &[Backends::GL];
+
+#[derive(Debug)]
+pub enum RendererError {
+  // E.g. the canvas to mount into doesn't exist
+  Window(Box<dyn Error>),
+  // None of the backends had an adapter, able to present to the window if there is one
+  NoAdapter(CapabilityReport),
+  RequestDevice(RequestDeviceError),
+  // The adapter has no format to present the surface with
+  UnsupportedSurface,
+  Texture(TextureError),
+  // Rendering and reading back frames headlessly
+  Render(SurfaceError),
+  Readback(BufferAsyncError),
+  Image(image::ImageError),
+}
+
+impl fmt::Display for RendererError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      RendererError::Window(e) => write!(f, "Unable to create window: {}", e),
+      RendererError::NoAdapter(report) => write!(f, "No graphics adapter available. {}", report),
+      RendererError::RequestDevice(e) => write!(f, "Failed to request device: {}", e),
+      RendererError::UnsupportedSurface => write!(f, "The adapter can't present to this window"),
+      RendererError::Texture(e) => e.fmt(f),
+      RendererError::Render(e) => write!(f, "Failed to render: {}", e),
+      RendererError::Readback(e) => write!(f, "Failed to read back frame: {}", e),
+      RendererError::Image(e) => write!(f, "Failed to write image: {}", e),
+    }
+  }
+}
+
+impl Error for RendererError {
+  fn source(&self) -> Option<&(dyn Error + 'static)> {
+    match self {
+      RendererError::Window(e) => Some(e.as_ref()),
+      RendererError::RequestDevice(e) => Some(e),
+      RendererError::Texture(e) => Some(e),
+      RendererError::Render(e) => Some(e),
+      RendererError::Readback(e) => Some(e),
+      RendererError::Image(e) => Some(e),
+      RendererError::NoAdapter(_) | RendererError::UnsupportedSurface => None,
+    }
+  }
+}
+
+impl From<RequestDeviceError> for RendererError {
+  fn from(e: RequestDeviceError) -> Self {
+    RendererError::RequestDevice(e)
+  }
+}
+
+impl From<TextureError> for RendererError {
+  fn from(e: TextureError) -> Self {
+    RendererError::Texture(e)
+  }
+}
+
+impl From<image::ImageError> for RendererError {
+  fn from(e: image::ImageError) -> Self {
+    RendererError::Image(e)
+  }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct AdapterAttempt {
+  pub backends: Backends,
+  pub force_fallback_adapter: bool,
+  pub adapter: Option<AdapterInfo>,
+}
+
+// What the platform supports and which adapters were tried, to tell users why nothing renders
+#[derive(Clone, Debug, PartialEq)]
+pub struct CapabilityReport {
+  // The API this build renders with: native, webgpu or webgl2
+  pub build: &'static str,
+  // Whether the browser exposes WebGPU and WebGL2, None when not running in a browser
+  pub webgpu: Option<bool>,
+  pub webgl2: Option<bool>,
+  pub attempts: Vec<AdapterAttempt>,
+}
+
+impl CapabilityReport {
+  #[cfg(not(target_arch = "wasm32"))]
+  pub(crate) fn probe() -> Self {
+    Self { build: BUILD, webgpu: None, webgl2: None, attempts: Vec::new() }
+  }
+
+  // Creating a surface for an API the browser lacks already fails, so check up front
+  #[cfg(target_arch = "wasm32")]
+  pub(crate) fn probe() -> Self {
+    use wasm_bindgen::JsCast;
+
+    let window = web_sys::window();
+    let webgpu = window.as_ref()
+      .and_then(|window| js_sys::Reflect::get(&window.navigator(), &"gpu".into()).ok())
+      .map(|gpu| !gpu.is_undefined());
+    let webgl2 = window.and_then(|window| window.document())
+      .and_then(|document| document.create_element("canvas").ok())
+      .and_then(|canvas| canvas.dyn_into::<web_sys::HtmlCanvasElement>().ok())
+      .map(|canvas| matches!(canvas.get_context("webgl2"), Ok(Some(_))));
+
+    Self { build: BUILD, webgpu, webgl2, attempts: Vec::new() }
+  }
+
+  // Whether the WebGL2 build could render where this one found no adapter
+  pub fn suggests_webgl2(&self) -> bool {
+    self.build == "webgpu" && self.webgl2 == Some(true)
+  }
+
+  fn supports(&self, backends: Backends) -> bool {
+    if backends.contains(Backends::BROWSER_WEBGPU) && self.webgpu == Some(false) {
+      return false;
+    }
+
+    !(cfg!(target_arch = "wasm32") && backends.contains(Backends::GL) && self.webgl2 == Some(false))
+  }
+}
+
+impl fmt::Display for CapabilityReport {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let tried = self.attempts.iter()
+      .map(|attempt| {
+        let fallback = if attempt.force_fallback_adapter { " (fallback adapter)" } else { "" };
+        let found = match &attempt.adapter {
+          Some(info) => format!("found {}", info.name),
+          None => "none found".to_string(),
+        };
+
+        format!("{}{}: {}", backend_names(attempt.backends), fallback, found)
+      })
+      .collect::<Vec<_>>();
+
+    if tried.is_empty() {
+      write!(f, "Tried no backends")?;
+    } else {
+      write!(f, "Tried {}", tried.join(", "))?;
+    }
+
+    match (self.build, self.webgpu, self.webgl2) {
+      ("webgpu", Some(false), Some(true)) => write!(f, ". This browser lacks WebGPU but supports WebGL2, use the webgl build"),
+      ("webgpu", Some(false), _) => write!(f, ". This browser lacks WebGPU"),
+      ("webgl2", _, Some(false)) => write!(f, ". This browser lacks WebGL2"),
+      _ => Ok(()),
+    }
+  }
+}
+
+fn backend_names(backends: Backends) -> String {
+  let gl = if cfg!(target_arch = "wasm32") { "WebGL2" } else { "GL" };
+  let names = [
+    (Backends::VULKAN, "Vulkan"),
+    (Backends::METAL, "Metal"),
+    (Backends::DX12, "DX12"),
+    (Backends::DX11, "DX11"),
+    (Backends::GL, gl),
+    (Backends::BROWSER_WEBGPU, "WebGPU"),
+  ];
+
+  names.iter()
+    .filter(|(backend, _)| backends.contains(*backend))
+    .map(|(_, name)| *name)
+    .collect::<Vec<_>>()
+    .join("/")
+}
+
+thread_local! {
+  // Held weakly, so the device is released once the last renderer using it is dropped
+  static SHARED: RefCell<Weak<GpuContext>> = const { RefCell::new(Weak::new()) };
+}
+
+// The adapter and device renderers draw with. Several viewers on a page share one context, so
+// resources aren't duplicated per canvas and the browser's limit on live devices isn't hit
+pub struct GpuContext {
+  pub device: Rc<Device>,
+  pub queue: Rc<Queue>,
+  pub adapter: Adapter,
+  pub report: CapabilityReport,
+  // Surfaces are created through the instance, it must outlive them
+  instance: Instance,
+}
+
+impl GpuContext {
+  // Creates a surface for the window along with the context to render to it with. The context
+  // of any renderer that's still alive is reused if its adapter can present to the window, in
+  // which case the config's power preference and limits don't apply
+  pub(crate) async fn with_surface(window: &Window, renderer: &RendererConfig) -> Result<(Rc<Self>, Surface), RendererError> {
+    if let Some(context) = SHARED.with(|shared| shared.borrow().upgrade()) {
+      let surface = unsafe { context.instance.create_surface(window) };
+
+      if context.adapter.is_surface_supported(&surface) {
+        return Ok((context, surface));
+      }
+
+      log::warn!("Shared adapter can't present to this window, creating another device");
+    }
+
+    let mut report = CapabilityReport::probe();
+    let (instance, surface, adapter) = match request_adapter(Some(window), renderer.power_preference, &mut report).await {
+      Some((instance, Some(surface), adapter)) => (instance, surface, adapter),
+      _ => return Err(RendererError::NoAdapter(report)),
+    };
+
+    let (device, queue) = request_device(&adapter, &renderer.limits).await?;
+
+    // Log all available features
+    log::info!("Adapter {:?}", adapter.get_info());
+    log::info!("Features \n    {:?}", adapter.features());
+    log::info!("{:#?}", adapter.limits());
+
+    let context = Rc::new(Self { device: Rc::new(device), queue: Rc::new(queue), adapter, report, instance });
+
+    SHARED.with(|shared| *shared.borrow_mut() = Rc::downgrade(&context));
+
+    Ok((context, surface))
+  }
+}
+
+// Goes through BACKENDS in order, then settles for a fallback (i.e. software) adapter on any of
+// them. Without a window, i.e. rendering headlessly, the fallback adapter is tried first instead
+// so output doesn't depend on the machine's GPU. Each attempt is recorded in the report
+pub(crate) async fn request_adapter(window: Option<&Window>, power_preference: PowerPreference, report: &mut CapabilityReport) -> Option<(Instance, Option<Surface>, Adapter)> {
+  let all = BACKENDS.iter().fold(Backends::empty(), |all, &backends| all | backends);
+  let fallback = (all, true);
+  let mut attempts: Vec<(Backends, bool)> = BACKENDS.iter().map(|&backends| (backends, false)).collect();
+
+  match window {
+    Some(_) => attempts.push(fallback),
+    None => attempts.insert(0, fallback),
+  }
+
+  for (backends, force_fallback_adapter) in attempts {
+    if !report.supports(backends) {
+      continue;
+    }
+
+    // Surfaces belong to an instance, so each set of backends needs its own
+    let instance = Instance::new(backends);
+    let surface = window.map(|window| unsafe { instance.create_surface(window) });
+    let adapter = instance.request_adapter(&RequestAdapterOptions {
+      power_preference,
+      compatible_surface: surface.as_ref(),
+      force_fallback_adapter,
+    }).await;
+
+    report.attempts.push(AdapterAttempt { backends, force_fallback_adapter, adapter: adapter.as_ref().map(Adapter::get_info) });
+
+    if let Some(adapter) = adapter {
+      return Some((instance, surface, adapter));
+    }
+  }
+
+  None
+}
+
+#[cfg(target_arch = "wasm32")]
+mod js {
+  use js_sys::{Array, Object, Reflect};
+  use wasm_bindgen::JsCast;
+  use wasm_bindgen::prelude::*;
+
+  use super::{backend_names, CapabilityReport, RendererError};
+
+  #[wasm_bindgen(typescript_custom_section)]
+  const TS_TYPES: &'static str = r#"
+export interface CapabilityReport {
+  build: "webgpu" | "webgl2";
+  webgpu?: boolean;
+  webgl2?: boolean;
+  attempts: { backends: string, forceFallbackAdapter: boolean, adapter?: string }[];
+}
+"#;
+
+  #[wasm_bindgen]
+  extern "C" {
+    #[wasm_bindgen(typescript_type = "CapabilityReport")]
+    pub type CapabilityReportObject;
+  }
+
+  impl CapabilityReport {
+    pub fn to_js(&self) -> CapabilityReportObject {
+      let attempts = self.attempts.iter()
+        .map(|attempt| object(&[
+          ("backends", backend_names(attempt.backends).into()),
+          ("forceFallbackAdapter", attempt.force_fallback_adapter.into()),
+          ("adapter", attempt.adapter.as_ref().map(|info| info.name.clone()).into()),
+        ]))
+        .collect::<Array>();
+
+      object(&[
+        ("build", self.build.into()),
+        ("webgpu", self.webgpu.into()),
+        ("webgl2", self.webgl2.into()),
+        ("attempts", attempts.into()),
+      ]).unchecked_into()
+    }
+  }
+
+  // Errors thrown to JS carry the report as `capabilities` when no adapter was found
+  impl From<RendererError> for JsValue {
+    fn from(e: RendererError) -> Self {
+      let error = js_sys::Error::new(&e.to_string());
+
+      if let RendererError::NoAdapter(report) = &e {
+        Reflect::set(&error, &"capabilities".into(), &report.to_js()).ok();
+      }
+
+      error.into()
+    }
+  }
+
+  fn object(properties: &[(&str, JsValue)]) -> JsValue {
+    let object = Object::new();
+
+    for (key, value) in properties {
+      Reflect::set(&object, &(*key).into(), value).ok();
+    }
+
+    object.into()
+  }
+}
+
+#[cfg(target_arch = "wasm32")]
+pub use js::CapabilityReportObject;
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn names_backends() {
+    assert_eq!(backend_names(Backends::PRIMARY), "Vulkan/Metal/DX12/WebGPU");
+    assert_eq!(backend_names(Backends::GL), "GL");
+  }
+
+  #[test]
+  fn reports_attempts_and_missing_apis() {
+    let report = CapabilityReport {
+      build: "webgpu",
+      webgpu: Some(false),
+      webgl2: Some(true),
+      attempts: vec![AdapterAttempt { backends: Backends::GL, force_fallback_adapter: true, adapter: None }],
+    };
+
+    assert!(!report.supports(Backends::BROWSER_WEBGPU));
+    assert_eq!(
+      report.to_string(),
+      "Tried GL (fallback adapter): none found. This browser lacks WebGPU but supports WebGL2, use the webgl build"
+    );
+  }
+
+  #[test]
+  fn suggests_webgl2_only_from_webgpu_build() {
+    let report = |build, webgl2| CapabilityReport { build, webgpu: Some(true), webgl2, attempts: Vec::new() };
+
+    assert!(report("webgpu", Some(true)).suggests_webgl2());
+    assert!(!report("webgpu", Some(false)).suggests_webgl2());
+    assert!(!report("webgpu", None).suggests_webgl2());
+    // Already on it, so no switching back and forth
+    assert!(!report("webgl2", Some(true)).suggests_webgl2());
+  }
+}
@@ -0,0 +1,470 @@
+use std::cell::RefCell;
+use std::mem;
+use std::ops::Range;
+use cgmath::{EuclideanSpace, InnerSpace, Matrix4, One, Point3, Quaternion, Transform as _, Vector3};
+use gltf::camera::Projection as GltfProjection;
+use gltf::texture::{MagFilter, MinFilter, WrappingMode};
+use gltf::Gltf;
+use wgpu::*;
+
+use crate::camera::{Camera, DepthMode, Projection};
+use crate::material::{Material, MaterialUniform};
+use crate::mesh::{Mesh, Vertex};
+use crate::model::{Model, ModelError};
+use crate::resources;
+use crate::mipmap::MipmapGenerator;
+use crate::sampler::{SamplerCache, SamplerOptions};
+use crate::texture_resource::{TextureOptions, TextureResource};
+use crate::transform::Transform;
+
+pub struct SceneNode {
+  pub name: String,
+  // Relative to the parent node
+  pub transform: Transform,
+  pub children: Vec<usize>,
+  // Meshes of the imported model drawn at this node, one per glTF primitive
+  pub meshes: Range<usize>,
+  // Index into the imported cameras
+  pub camera: Option<usize>,
+}
+
+// Everything imported from a glTF file. Nodes keep their glTF indices, so children refer to
+// positions in `nodes`
+pub struct GltfScene {
+  pub nodes: Vec<SceneNode>,
+  // Nodes at the top of the default scene
+  pub roots: Vec<usize>,
+  pub model: Model,
+  // Cameras placed by the scene's nodes, posed at their node's world transform
+  pub cameras: Vec<Camera>,
+}
+
+// Loads a .gltf or .glb file. External buffers and images are resolved relative to the url,
+// embedded ones are read from the binary chunk or from base64 data uris
+pub async fn load_gltf(device: &Device, queue: &Queue, samplers: &RefCell<SamplerCache>, mipmaps: &RefCell<MipmapGenerator>, layout: &BindGroupLayout, url: &str) -> Result<GltfScene, ModelError> {
+  let bytes = resources::load_bytes(url).await?;
+  let gltf = Gltf::from_slice(&bytes)?;
+
+  let mut buffers = Vec::new();
+
+  for buffer in gltf.buffers() {
+    let data = match buffer.source() {
+      gltf::buffer::Source::Bin => gltf.blob.clone()
+        .ok_or_else(|| ModelError::InvalidGltf("Missing binary chunk".into()))?,
+      gltf::buffer::Source::Uri(uri) => load_uri(url, uri).await?,
+    };
+
+    buffers.push(data);
+  }
+
+  let mut images = Vec::new();
+
+  for image in gltf.images() {
+    let data = match image.source() {
+      gltf::image::Source::View { view, .. } => {
+        let buffer = &buffers[view.buffer().index()];
+
+        buffer.get(view.offset()..view.offset() + view.length())
+          .ok_or_else(|| ModelError::InvalidGltf(format!("Image {} is out of bounds", image.index())))?
+          .to_vec()
+      }
+      gltf::image::Source::Uri { uri, .. } => load_uri(url, uri).await?,
+    };
+
+    images.push(data);
+  }
+
+  // Everything is loaded, the rest doesn't wait so the caches can stay borrowed
+  let samplers = &mut *samplers.borrow_mut();
+  let mut textures = TextureLoader { device, queue, samplers: &mut *samplers, mipmaps: &mut mipmaps.borrow_mut(), images: &images };
+  let mut materials = Vec::with_capacity(gltf.materials().len() + 1);
+
+  for material in gltf.materials() {
+    let name = material.name().unwrap_or("material");
+    let pbr = material.pbr_metallic_roughness();
+
+    let diffuse_texture = match pbr.base_color_texture() {
+      Some(info) => textures.load(&info.texture(), name, false)?,
+      None => TextureResource::from_color(device, queue, textures.samplers, [255; 4], name)?,
+    };
+
+    let uniform = MaterialUniform {
+      metallic: pbr.metallic_factor(),
+      roughness: pbr.roughness_factor(),
+      ..MaterialUniform::new(pbr.base_color_factor())
+    };
+
+    let mut material_resource = Material::from_uniform(device, layout, name, diffuse_texture, uniform);
+    let tex_coord = tex_coord_set(&material);
+    let mut other_sets = pbr.metallic_roughness_texture().map(|info| info.tex_coord()).into_iter()
+      .chain(material.normal_texture().map(|normal| normal.tex_coord()));
+
+    if other_sets.any(|set| set != tex_coord) {
+      log::warn!("Material {} uses several texture coordinate sets, all its textures are mapped with TEXCOORD_{}", name, tex_coord);
+    }
+
+    // Both hold data rather than color, so skip the sRGB conversion
+    if let Some(info) = pbr.metallic_roughness_texture() {
+      material_resource.metallic_roughness_texture = Some(textures.load(&info.texture(), name, true)?);
+    }
+
+    if let Some(normal) = material.normal_texture() {
+      material_resource.normal_texture = Some(textures.load(&normal.texture(), name, true)?);
+    }
+
+    materials.push(material_resource);
+  }
+
+  // Primitives without a material are drawn plain white
+  let mut meshes = Vec::new();
+  let mut mesh_ranges = Vec::with_capacity(gltf.meshes().len());
+
+  for gltf_mesh in gltf.meshes() {
+    let start = meshes.len();
+
+    for primitive in gltf_mesh.primitives() {
+      let name = format!("{}.{}", gltf_mesh.name().unwrap_or("mesh"), primitive.index());
+
+      if primitive.mode() != gltf::mesh::Mode::Triangles {
+        log::warn!("Skipping {}, only triangle lists are supported", name);
+        continue;
+      }
+
+      let reader = primitive.reader(|buffer| buffers.get(buffer.index()).map(Vec::as_slice));
+      let positions: Vec<[f32; 3]> = match reader.read_positions() {
+        Some(positions) => positions.collect(),
+        None => {
+          log::warn!("Skipping {}, it has no positions", name);
+          continue;
+        }
+      };
+
+      let normals: Option<Vec<[f32; 3]>> = reader.read_normals().map(Iterator::collect);
+      let tangents: Option<Vec<[f32; 4]>> = reader.read_tangents().map(Iterator::collect);
+      let tex_coord = tex_coord_set(&primitive.material());
+      let texture_coords: Option<Vec<[f32; 2]>> = reader.read_tex_coords(tex_coord).map(|coords| coords.into_f32().collect());
+
+      if texture_coords.is_none() && primitive.material().pbr_metallic_roughness().base_color_texture().is_some() {
+        log::warn!("{} has no TEXCOORD_{} for its texture", name, tex_coord);
+      }
+
+      let colors: Option<Vec<[f32; 3]>> = reader.read_colors(0).map(|colors| colors.into_rgb_f32().collect());
+
+      let vertex_count = positions.len();
+      let check_count = |attribute: &str, count: Option<usize>| match count {
+        Some(count) if count != vertex_count => Err(ModelError::InvalidGltf(
+          format!("{} has {} positions but {} {}", name, vertex_count, count, attribute)
+        )),
+        _ => Ok(()),
+      };
+
+      check_count("normals", normals.as_ref().map(Vec::len))?;
+      check_count("tangents", tangents.as_ref().map(Vec::len))?;
+      check_count("texture coordinates", texture_coords.as_ref().map(Vec::len))?;
+      check_count("colors", colors.as_ref().map(Vec::len))?;
+
+      let vertices: Vec<Vertex> = positions.iter().enumerate().map(|(i, position)| Vertex {
+        position: *position,
+        color: colors.as_ref().map_or([1., 1., 1.], |colors| colors[i]),
+        // glTF texture coordinates have their origin in the upper left, flip them to match
+        // our vertex data
+        texture_coords: texture_coords.as_ref().map_or([0., 0.], |coords| [coords[i][0], 1. - coords[i][1]]),
+        normal: normals.as_ref().map_or([0., 0., 1.], |normals| normals[i]),
+        tangent: tangents.as_ref().map_or([1., 0., 0., 1.], |tangents| tangents[i]),
+      }).collect();
+
+      // Non-indexed primitives draw their vertices in order
+      let indices: Vec<u32> = match reader.read_indices() {
+        Some(indices) => indices.into_u32().collect(),
+        None => (0..vertices.len() as u32).collect(),
+      };
+
+      if let Some(index) = indices.iter().find(|index| **index as usize >= vertex_count) {
+        return Err(ModelError::InvalidGltf(format!("{} indexes vertex {} of {}", name, index, vertex_count)));
+      }
+
+      meshes.push((Mesh::with_u32_indices(device, &name, &vertices, &indices), primitive.material().index()));
+    }
+
+    mesh_ranges.push(start..meshes.len());
+  }
+
+  let model = Model::with_default_material(device, queue, samplers, layout, meshes, materials)?;
+  import_nodes(&gltf, &mesh_ranges, model)
+}
+
+// Builds the node hierarchy of the default scene around the imported model, whose meshes are
+// given per glTF mesh
+fn import_nodes(gltf: &Gltf, mesh_ranges: &[Range<usize>], model: Model) -> Result<GltfScene, ModelError> {
+  let gltf_nodes: Vec<gltf::Node> = gltf.nodes().collect();
+  let mut nodes: Vec<SceneNode> = gltf_nodes.iter().map(|node| {
+    let (translation, rotation, scale) = node.transform().decomposed();
+
+    SceneNode {
+      name: node.name().unwrap_or("node").to_string(),
+      transform: Transform {
+        translation: translation.into(),
+        // glTF stores quaternions as xyzw
+        rotation: Quaternion::new(rotation[3], rotation[0], rotation[1], rotation[2]),
+        scale: scale.into(),
+      },
+      children: node.children().map(|child| child.index()).collect(),
+      meshes: node.mesh().map_or(0..0, |mesh| mesh_ranges[mesh.index()].clone()),
+      camera: None,
+    }
+  }).collect();
+
+  let roots: Vec<usize> = match gltf.default_scene().or_else(|| gltf.scenes().next()) {
+    Some(scene) => scene.nodes().map(|node| node.index()).collect(),
+    None => Vec::new(),
+  };
+
+  // Cameras need their world transform, so walk the hierarchy down from the roots. glTF nodes
+  // have at most one parent, so reaching a node twice means the file has a cycle
+  let mut cameras = Vec::new();
+  let mut visited = vec![false; nodes.len()];
+  let mut stack: Vec<(usize, Matrix4<f32>)> = roots.iter().map(|root| (*root, Matrix4::one())).collect();
+
+  while let Some((index, parent)) = stack.pop() {
+    if mem::replace(&mut visited[index], true) {
+      return Err(ModelError::InvalidGltf(format!("Node {} is reached more than once", index)));
+    }
+
+    let world = parent * nodes[index].transform.matrix();
+
+    if let Some(camera) = gltf_nodes[index].camera() {
+      nodes[index].camera = Some(cameras.len());
+      cameras.push(camera_from_gltf(&camera, world));
+    }
+
+    stack.extend(nodes[index].children.iter().map(|child| (*child, world)));
+  }
+
+  Ok(GltfScene { nodes, roots, model, cameras })
+}
+
+// Reads a buffer or image uri, either inline or relative to the file that references it
+async fn load_uri(base: &str, uri: &str) -> Result<Vec<u8>, ModelError> {
+  match uri.strip_prefix("data:") {
+    Some(data) => decode_data_uri(data),
+    None => Ok(resources::load_bytes(&resources::resolve_url(base, uri)).await?),
+  }
+}
+
+// Vertices carry a single set of texture coordinates, the one the base color texture is mapped
+// with
+fn tex_coord_set(material: &gltf::Material) -> u32 {
+  material.pbr_metallic_roughness().base_color_texture().map_or(0, |info| info.tex_coord())
+}
+
+// Decodes the part of a data uri following `data:`, glTF only embeds base64 data
+fn decode_data_uri(data: &str) -> Result<Vec<u8>, ModelError> {
+  let (_, encoded) = data.split_once(";base64,")
+    .ok_or_else(|| ModelError::InvalidGltf("Data uri is not base64 encoded".into()))?;
+
+  base64::decode(encoded).map_err(|e| ModelError::InvalidGltf(format!("Invalid data uri: {}", e)))
+}
+
+// Creates the textures of the file's materials from its images
+struct TextureLoader<'a> {
+  device: &'a Device,
+  queue: &'a Queue,
+  samplers: &'a mut SamplerCache,
+  mipmaps: &'a mut MipmapGenerator,
+  images: &'a [Vec<u8>],
+}
+
+impl TextureLoader<'_> {
+  fn load(&mut self, texture: &gltf::Texture, label: &str, linear: bool) -> Result<TextureResource, ModelError> {
+    let options = TextureOptions {
+      mipmaps: true,
+      linear,
+      sampler: sampler_options(&texture.sampler()),
+    };
+    let image = &self.images[texture.source().index()];
+
+    Ok(TextureResource::from_bytes(self.device, self.queue, self.samplers, self.mipmaps, image, label, &options)?)
+  }
+}
+
+fn sampler_options(sampler: &gltf::texture::Sampler) -> SamplerOptions {
+  let address_mode = |mode| match mode {
+    WrappingMode::ClampToEdge => AddressMode::ClampToEdge,
+    WrappingMode::MirroredRepeat => AddressMode::MirrorRepeat,
+    WrappingMode::Repeat => AddressMode::Repeat,
+  };
+
+  let defaults = SamplerOptions::default();
+  let (min_filter, mipmap_filter) = match sampler.min_filter() {
+    Some(MinFilter::Nearest | MinFilter::NearestMipmapNearest) => (FilterMode::Nearest, FilterMode::Nearest),
+    Some(MinFilter::NearestMipmapLinear) => (FilterMode::Nearest, FilterMode::Linear),
+    Some(MinFilter::Linear | MinFilter::LinearMipmapNearest) => (FilterMode::Linear, FilterMode::Nearest),
+    Some(MinFilter::LinearMipmapLinear) => (FilterMode::Linear, FilterMode::Linear),
+    None => (defaults.min_filter, defaults.mipmap_filter),
+  };
+
+  SamplerOptions {
+    address_mode_u: address_mode(sampler.wrap_s()),
+    address_mode_v: address_mode(sampler.wrap_t()),
+    mag_filter: match sampler.mag_filter() {
+      Some(MagFilter::Nearest) => FilterMode::Nearest,
+      _ => FilterMode::Linear,
+    },
+    min_filter,
+    mipmap_filter,
+    ..defaults
+  }
+}
+
+// glTF cameras look down their node's -Z with +Y up. A perspective camera without a far plane
+// asks for an infinite projection, which we can only do with reverse-z
+pub fn camera_from_gltf(camera: &gltf::Camera, world: Matrix4<f32>) -> Camera {
+  let eye = world.transform_point(Point3::origin());
+  let forward = world.transform_vector(-Vector3::unit_z()).normalize();
+  let up = world.transform_vector(Vector3::unit_y()).normalize();
+
+  let mut result = Camera {
+    eye,
+    target: eye + forward,
+    up,
+    ..Camera::new(1.)
+  };
+
+  match camera.projection() {
+    GltfProjection::Perspective(perspective) => {
+      result.projection = Projection::Perspective { fovy: perspective.yfov().to_degrees() };
+      result.aspect = perspective.aspect_ratio().unwrap_or(result.aspect);
+      result.znear = perspective.znear();
+
+      match perspective.zfar() {
+        Some(zfar) => result.zfar = zfar,
+        None => result.depth_mode = DepthMode::ReverseZ,
+      }
+    }
+    GltfProjection::Orthographic(orthographic) => {
+      result.projection = Projection::Orthographic { height: 2. * orthographic.ymag() };
+      result.aspect = orthographic.xmag() / orthographic.ymag();
+      result.znear = orthographic.znear();
+      result.zfar = orthographic.zfar();
+    }
+  }
+
+  result
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use cgmath::{Deg, Rotation3};
+
+  fn parse(json: &str) -> Gltf {
+    Gltf::from_slice(json.as_bytes()).expect("Valid gltf")
+  }
+
+  fn empty_model() -> Model {
+    Model { meshes: Vec::new(), materials: Vec::new() }
+  }
+
+  #[test]
+  fn decodes_base64_data_uri() {
+    let data = decode_data_uri("application/octet-stream;base64,AAECAw==").unwrap();
+
+    assert_eq!(data, vec![0, 1, 2, 3]);
+  }
+
+  #[test]
+  fn rejects_data_uri_without_base64() {
+    assert!(matches!(decode_data_uri("text/plain,hello"), Err(ModelError::InvalidGltf(_))));
+  }
+
+  #[test]
+  fn maps_perspective_camera() {
+    let gltf = parse(r#"{
+      "asset": { "version": "2.0" },
+      "cameras": [{ "type": "perspective", "perspective": { "yfov": 1.0, "aspectRatio": 1.5, "znear": 0.5, "zfar": 50.0 } }]
+    }"#);
+    let world = Matrix4::from_translation(Vector3::new(0., 0., 5.));
+    let camera = camera_from_gltf(&gltf.cameras().next().unwrap(), world);
+
+    assert_eq!(camera.eye, Point3::new(0., 0., 5.));
+    assert_eq!(camera.target, Point3::new(0., 0., 4.));
+    assert_eq!(camera.projection, Projection::Perspective { fovy: 1f32.to_degrees() });
+    assert_eq!(camera.aspect, 1.5);
+    assert_eq!((camera.znear, camera.zfar), (0.5, 50.));
+    assert_eq!(camera.depth_mode, DepthMode::Standard);
+  }
+
+  #[test]
+  fn maps_infinite_perspective_camera_to_reverse_z() {
+    let gltf = parse(r#"{
+      "asset": { "version": "2.0" },
+      "cameras": [{ "type": "perspective", "perspective": { "yfov": 1.0, "znear": 0.1 } }]
+    }"#);
+    let camera = camera_from_gltf(&gltf.cameras().next().unwrap(), Matrix4::one());
+
+    assert_eq!(camera.depth_mode, DepthMode::ReverseZ);
+  }
+
+  #[test]
+  fn maps_orthographic_camera() {
+    let gltf = parse(r#"{
+      "asset": { "version": "2.0" },
+      "cameras": [{ "type": "orthographic", "orthographic": { "xmag": 4.0, "ymag": 2.0, "znear": 0.1, "zfar": 10.0 } }]
+    }"#);
+    // Turned to look down -X
+    let world = Matrix4::from(Quaternion::from_angle_y(Deg(90.)));
+    let camera = camera_from_gltf(&gltf.cameras().next().unwrap(), world);
+
+    assert_eq!(camera.projection, Projection::Orthographic { height: 4. });
+    assert_eq!(camera.aspect, 2.);
+    assert!((camera.target.x + 1.).abs() < 1e-6);
+    assert!(camera.target.z.abs() < 1e-6);
+  }
+
+  #[test]
+  fn reads_base_color_tex_coord_set() {
+    let gltf = parse(r#"{
+      "asset": { "version": "2.0" },
+      "images": [{ "uri": "image.png" }],
+      "textures": [{ "source": 0 }],
+      "materials": [
+        { "pbrMetallicRoughness": { "baseColorTexture": { "index": 0, "texCoord": 1 } } },
+        { "normalTexture": { "index": 0, "texCoord": 1 } }
+      ]
+    }"#);
+    let sets: Vec<u32> = gltf.materials().map(|material| tex_coord_set(&material)).collect();
+
+    assert_eq!(sets, vec![1, 0]);
+  }
+
+  #[test]
+  fn rejects_node_cycles() {
+    let gltf = parse(r#"{
+      "asset": { "version": "2.0" },
+      "scene": 0,
+      "scenes": [{ "nodes": [0] }],
+      "nodes": [{ "children": [1] }, { "children": [0] }]
+    }"#);
+
+    assert!(matches!(import_nodes(&gltf, &[], empty_model()), Err(ModelError::InvalidGltf(_))));
+  }
+
+  #[test]
+  fn imports_node_hierarchy() {
+    let gltf = parse(r#"{
+      "asset": { "version": "2.0" },
+      "scene": 0,
+      "scenes": [{ "nodes": [0] }],
+      "cameras": [{ "type": "perspective", "perspective": { "yfov": 1.0, "znear": 0.5 } }],
+      "nodes": [{ "name": "root", "children": [1], "translation": [0, 0, 5] }, { "camera": 0 }]
+    }"#);
+
+    let GltfScene { nodes, roots, cameras, .. } = import_nodes(&gltf, &[], empty_model()).unwrap();
+
+    assert_eq!(roots, vec![0]);
+    assert_eq!(nodes[0].name, "root");
+    assert_eq!(nodes[0].children, vec![1]);
+    assert_eq!(nodes[1].camera, Some(0));
+    assert_eq!(cameras[0].eye, Point3::new(0., 0., 5.));
+  }
+}
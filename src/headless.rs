@@ -0,0 +1,186 @@
+use std::num::NonZeroU32;
+use std::path::Path;
+use std::rc::Rc;
+use image::RgbaImage;
+use wgpu::*;
+
+use crate::camera::Camera;
+use crate::texture_resource;
+use crate::config::RendererConfig;
+use crate::context::{self, CapabilityReport, RendererError};
+use crate::instance::InstanceData;
+use crate::model::ModelError;
+use crate::scene::{NodeId, Scene};
+use crate::transform::Transform;
+use crate::{create_offscreen_texture, request_device, RenderTarget, State};
+
+// Format of the offscreen target, and therefore of the captured pixels
+pub const FORMAT: TextureFormat = TextureFormat::Rgba8UnormSrgb;
+
+// Renders a single frame of the default scene and reads it back
+pub fn render_to_image(width: u32, height: u32) -> Result<RgbaImage, RendererError> {
+  render_to_image_with_config(&RendererConfig { width, height, ..Default::default() })
+}
+
+// The config's present mode doesn't apply, frames are never presented
+pub fn render_to_image_with_config(config: &RendererConfig) -> Result<RgbaImage, RendererError> {
+  HeadlessRenderer::new(config)?.render()
+}
+
+// Renders a single frame of the default scene into an image file, the format follows from the
+// path's extension
+pub fn render_to_file(path: impl AsRef<Path>, width: u32, height: u32) -> Result<(), RendererError> {
+  render_to_image(width, height)?.save(path)?;
+
+  Ok(())
+}
+
+// Renders offscreen like render_to_image, but keeps the scene around so it can be built up and
+// changed between frames. Frames don't depend on timing, the camera and scene are drawn as set
+pub struct HeadlessRenderer {
+  state: State,
+}
+
+impl HeadlessRenderer {
+  // Blocks until the device is ready. The scene starts out with the default quad
+  pub fn new(config: &RendererConfig) -> Result<Self, RendererError> {
+    let state = pollster::block_on(State::new_headless(config))?;
+
+    Ok(Self { state })
+  }
+
+  pub fn scene(&self) -> &Scene {
+    &self.state.scene
+  }
+
+  // Node drawing the default quad
+  pub fn quad(&self) -> NodeId {
+    self.state.quad_node
+  }
+
+  // Adds an obj or glTF file at the root of the scene, returning the nodes it was added as. If
+  // the glTF file has a camera, we look through it
+  pub fn load_model(&mut self, path: &str) -> Result<Vec<NodeId>, ModelError> {
+    pollster::block_on(self.state.load_model(path))
+  }
+
+  // See Scene::set_instances
+  pub fn set_instances(&mut self, node: NodeId, instances: &[InstanceData]) -> bool {
+    self.state.set_instances(node, instances)
+  }
+
+  pub fn transform_mut(&mut self, node: NodeId) -> &mut Transform {
+    self.state.scene.transform_mut(node)
+  }
+
+  // See Scene::set_parent
+  pub fn set_parent(&mut self, node: NodeId, parent: Option<NodeId>) -> bool {
+    self.state.scene.set_parent(node, parent)
+  }
+
+  pub fn camera_mut(&mut self) -> &mut Camera {
+    &mut self.state.camera
+  }
+
+  // Renders a frame and reads it back
+  pub fn render(&mut self) -> Result<RgbaImage, RendererError> {
+    self.state.update_still();
+    self.state.render().map_err(RendererError::Render)?;
+
+    pollster::block_on(self.state.capture())
+  }
+}
+
+impl State {
+  // Renders into a texture instead of a window. A software adapter is preferred so that output
+  // doesn't depend on the machine's GPU, e.g. on CI, but any adapter will do
+  pub(crate) async fn new_headless(renderer: &RendererConfig) -> Result<Self, RendererError> {
+    let RendererConfig { width, height, .. } = *renderer;
+    let mut report = CapabilityReport::probe();
+    let adapter = match context::request_adapter(None, renderer.power_preference, &mut report).await {
+      Some((_, _, adapter)) => adapter,
+      None => return Err(RendererError::NoAdapter(report)),
+    };
+
+    log::info!("Headless adapter {:?}", adapter.get_info());
+
+    let (device, queue) = request_device(&adapter, &renderer.limits).await?;
+
+    texture_resource::check_size(width, height, &device.limits())?;
+
+    let config = SurfaceConfiguration {
+      // COPY_SRC so the frame can be copied into a buffer for reading back
+      usage: TextureUsages::RENDER_ATTACHMENT | TextureUsages::COPY_SRC,
+      format: FORMAT,
+      width,
+      height,
+      present_mode: PresentMode::Fifo
+    };
+
+    let target = RenderTarget::Offscreen(create_offscreen_texture(&device, &config));
+
+    Ok(Self::with_target(Rc::new(device), Rc::new(queue), target, config, renderer)?)
+  }
+
+  // Copies the last rendered frame back to the CPU
+  pub(crate) async fn capture(&self) -> Result<RgbaImage, RendererError> {
+    let texture = match &self.target {
+      RenderTarget::Offscreen(texture) => texture,
+      RenderTarget::Surface(_) => panic!("Only offscreen targets can be captured"),
+    };
+
+    let width = self.config.width;
+    let height = self.config.height;
+    let unpadded_bytes_per_row = 4 * width;
+    // Buffer copies require each row to start on a 256 byte boundary
+    let align = COPY_BYTES_PER_ROW_ALIGNMENT;
+    let padded_bytes_per_row = unpadded_bytes_per_row.div_ceil(align) * align;
+
+    let buffer = self.device.create_buffer(&BufferDescriptor {
+      label: Some("Capture buffer"),
+      size: (padded_bytes_per_row * height) as BufferAddress,
+      usage: BufferUsages::COPY_DST | BufferUsages::MAP_READ,
+      mapped_at_creation: false
+    });
+
+    let mut encoder = self.device.create_command_encoder(&CommandEncoderDescriptor {
+      label: Some("Capture encoder"),
+    });
+
+    encoder.copy_texture_to_buffer(
+      ImageCopyTexture {
+        texture,
+        mip_level: 0,
+        origin: Origin3d::ZERO,
+        aspect: TextureAspect::All
+      },
+      ImageCopyBuffer {
+        buffer: &buffer,
+        layout: ImageDataLayout {
+          offset: 0,
+          bytes_per_row: NonZeroU32::new(padded_bytes_per_row),
+          rows_per_image: NonZeroU32::new(height),
+        }
+      },
+      Extent3d { width, height, depth_or_array_layers: 1 }
+    );
+
+    self.queue.submit(std::iter::once(encoder.finish()));
+
+    let slice = buffer.slice(..);
+    let mapping = slice.map_async(MapMode::Read);
+
+    // Blocks until the copy is done and the mapping callback has fired
+    self.device.poll(Maintain::Wait);
+    mapping.await.map_err(RendererError::Readback)?;
+
+    let padded = slice.get_mapped_range();
+    let pixels = padded
+      .chunks(padded_bytes_per_row as usize)
+      .flat_map(|row| &row[..unpadded_bytes_per_row as usize])
+      .copied()
+      .collect();
+
+    Ok(RgbaImage::from_raw(width, height, pixels).expect("Capture buffer matches image size"))
+  }
+}
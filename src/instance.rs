@@ -0,0 +1,115 @@
+use std::mem;
+use bytemuck::{Pod, Zeroable, cast_slice};
+use cgmath::{Matrix4, One};
+use wgpu::util::{BufferInitDescriptor, DeviceExt};
+use wgpu::*;
+
+use crate::transform::Transform;
+
+// Per-instance vertex data. The model matrix is relative to the node drawing the instances
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq, Pod, Zeroable)]
+pub struct InstanceData {
+  pub model_mat: [[f32; 4]; 4],
+  // Multiplied with the material color
+  pub color: [f32; 4],
+}
+
+impl Default for InstanceData {
+  fn default() -> Self {
+    Self::new(Matrix4::one(), [1.; 4])
+  }
+}
+
+impl InstanceData {
+  // Vertex attributes can be at most a vec4, so the matrix takes up four locations
+  const LAYOUT: [VertexAttribute; 5] = vertex_attr_array![
+    5 => Float32x4, 6 => Float32x4, 7 => Float32x4, 8 => Float32x4,
+    9 => Float32x4
+  ];
+
+  pub fn new(model_mat: Matrix4<f32>, color: [f32; 4]) -> Self {
+    Self { model_mat: model_mat.into(), color }
+  }
+
+  pub fn from_transform(transform: &Transform, color: [f32; 4]) -> Self {
+    Self::new(transform.matrix(), color)
+  }
+
+  pub fn desc<'a>() -> VertexBufferLayout<'a> {
+    VertexBufferLayout {
+      array_stride: mem::size_of::<InstanceData>() as BufferAddress,
+      // Advance once per instance rather than once per vertex
+      step_mode: VertexStepMode::Instance,
+      attributes: &Self::LAYOUT
+    }
+  }
+}
+
+// Instance data bound to vertex buffer slot 1. Grows as needed when updated, so a layer can
+// change its number of features without recreating the node
+pub struct InstanceBuffer {
+  pub buffer: Buffer,
+  label: String,
+  count: u32,
+  capacity: usize,
+}
+
+impl InstanceBuffer {
+  pub fn new(device: &Device, label: &str, instances: &[InstanceData]) -> Self {
+    // Empty buffers can't be bound, keep room for at least one instance
+    let contents = if instances.is_empty() { vec![InstanceData::default()] } else { instances.to_vec() };
+    let buffer = device.create_buffer_init(&BufferInitDescriptor {
+      label: Some(label),
+      contents: cast_slice(&contents),
+      usage: BufferUsages::VERTEX | BufferUsages::COPY_DST
+    });
+
+    Self { buffer, label: label.to_string(), count: instances.len() as u32, capacity: contents.len() }
+  }
+
+  // A single instance at the origin, bound for meshes drawn without instances
+  pub fn identity(device: &Device) -> Self {
+    Self::new(device, "Identity instance buf", &[InstanceData::default()])
+  }
+
+  pub fn count(&self) -> u32 {
+    self.count
+  }
+
+  pub fn update(&mut self, device: &Device, queue: &Queue, instances: &[InstanceData]) {
+    if instances.len() > self.capacity {
+      *self = Self::new(device, &self.label, instances);
+      return;
+    }
+
+    if !instances.is_empty() {
+      queue.write_buffer(&self.buffer, 0, cast_slice(instances));
+    }
+
+    self.count = instances.len() as u32;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use cgmath::Vector3;
+
+  #[test]
+  fn layout_covers_instance_data() {
+    let desc = InstanceData::desc();
+    let last = desc.attributes.last().unwrap();
+
+    assert_eq!(desc.step_mode, VertexStepMode::Instance);
+    assert_eq!(last.offset + last.format.size(), desc.array_stride);
+  }
+
+  #[test]
+  fn from_transform_stores_column_major_matrix() {
+    let transform = Transform { translation: Vector3::new(1., 2., 3.), ..Default::default() };
+    let instance = InstanceData::from_transform(&transform, [1.; 4]);
+
+    assert_eq!(instance.model_mat[3], [1., 2., 3., 1.]);
+  }
+}
@@ -1,199 +1,280 @@
-mod texture_resource;
-mod camera; 
-
+pub mod texture_resource;
+pub mod camera; 
+pub mod clock;
+pub mod config;
+mod camera_controller;
+pub mod context;
+pub mod mipmap;
+pub mod wgsl;
+pub mod mesh;
+pub mod material;
+pub mod model;
+pub mod gltf_import;
+pub mod scene;
+pub mod instance;
+pub mod transform;
+pub mod resources;
+pub mod sampler;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod headless;
+#[cfg(target_arch = "wasm32")]
+pub mod canvas;
+#[cfg(target_arch = "wasm32")]
+pub mod viewer;
+
+use std::cell::RefCell;
 use std::error::Error;
-use std::mem;
-use bytemuck::Pod;
-use bytemuck::Zeroable;
+use std::rc::Rc;
+use std::time::Duration;
 use bytemuck::cast_slice;
 use camera::Camera;
+use camera::DepthMode;
+#[cfg(target_arch = "wasm32")]
+use cgmath::Point3;
 use camera::CameraUniform;
+use camera_controller::CameraController;
+use camera_controller::CameraMode;
+use clock::FrameClock;
+use clock::FrameUniform;
+use config::RendererConfig;
+use context::{GpuContext, RendererError};
+use material::Material;
+use instance::InstanceBuffer;
+use instance::InstanceData;
+use mesh::Indices;
+use mesh::Mesh;
+use mesh::Vertex;
+use gltf_import::GltfScene;
+use model::Model;
+use model::ModelError;
+use scene::{NodeId, Scene};
+use mipmap::MipmapGenerator;
+use sampler::SamplerCache;
+use texture_resource::TextureOptions;
+use texture_resource::TextureError;
 use texture_resource::TextureResource;
+use transform::Transform;
+#[cfg(target_arch = "wasm32")]
 use wasm_bindgen::prelude::*;
-use web_sys::console;
 use wgpu::TextureUsages;
 use wgpu::util::BufferInitDescriptor;
 use wgpu::util::DeviceExt;
 use wgpu::*; 
 use winit::{
   event::*,
-  event_loop::{EventLoop},
+  event_loop::{EventLoop, ControlFlow},
   window::{WindowBuilder, Window}, dpi::PhysicalSize,
-  platform::web::WindowExtWebSys
 };
+#[cfg(target_arch = "wasm32")]
+use canvas::{CanvasResizer, Mount};
+#[cfg(target_arch = "wasm32")]
+use winit::platform::web::{WindowBuilderExtWebSys, WindowExtWebSys};
+
+
+
+const VERTS: &[Vertex] = &[
+  Vertex { position: [-0.5,  0.5, 0.0], color: [1., 0.0, 0.0], texture_coords: [0., 1.], normal: [0., 0., 1.], tangent: [1., 0., 0., 1.] },
+  Vertex { position: [-0.5, -0.5, 0.0], color: [0.0, 1., 0.0], texture_coords: [0., 0.], normal: [0., 0., 1.], tangent: [1., 0., 0., 1.] },
+  Vertex { position: [ 0.5, -0.5, 0.0], color: [0.0, 0.0, 1.], texture_coords: [1., 0.], normal: [0., 0., 1.], tangent: [1., 0., 0., 1.] },
+  Vertex { position: [ 0.5,  0.5, 0.0], color: [0.0, 0.0, 1.], texture_coords: [1., 1.], normal: [0., 0., 1.], tangent: [1., 0., 0., 1.] },
+]; 
 
+const INDICES: &[u16] = &[
+  0, 1, 2,
+  0, 2, 3,
+];
 
+// Rate at which the simulation (currently just the camera controllers) advances
+const SIMULATION_STEP: Duration = Duration::from_micros(1_000_000 / 120);
 
-#[repr(C)]
-#[derive(Copy, Clone, Debug, Pod, Zeroable)]
-struct Vertex {
-  position: [f32; 3],
-  color: [f32; 3],
-  texture_coords: [f32; 2]
+// Where frames end up. Offscreen targets are used for headless rendering, where the frame is
+// copied back to the CPU instead of being presented
+enum RenderTarget {
+  Surface(Surface),
+  #[cfg(not(target_arch = "wasm32"))]
+  Offscreen(Texture),
 }
 
-impl Vertex {
-  // Workaround for rust bug? 
-  const LAYOUT: [VertexAttribute; 3] =
-    vertex_attr_array![0 => Float32x3, 1 => Float32x3, 2 => Float32x2]; 
-  
-  fn desc<'a>() -> VertexBufferLayout<'a> {
-    let array_stride = mem::size_of::<Vertex>() as BufferAddress;
-    
-    // VertexBufferLayout {
-    //     array_stride,
-    //     step_mode: VertexStepMode::Vertex,
-    //     attributes: &[
-    //         VertexAttribute {
-    //             shader_location: 0, 
-    //             format: VertexFormat::Float32x3, 
-    //             offset: 0,
-    //         },
-    //         VertexAttribute {
-    //             shader_location: 1, 
-    //             format: VertexFormat::Float32x3, 
-    //             offset: mem::size_of::<[f32; 3]>() as BufferAddress,
-    //         }
-    //     ]
-    // }
-
-    VertexBufferLayout {
-      array_stride,
-      step_mode: VertexStepMode::Vertex,
-      attributes: &Self::LAYOUT
+impl RenderTarget {
+  fn configure(&mut self, device: &Device, config: &SurfaceConfiguration) {
+    match self {
+      RenderTarget::Surface(surface) => surface.configure(device, config),
+      #[cfg(not(target_arch = "wasm32"))]
+      RenderTarget::Offscreen(texture) => *texture = create_offscreen_texture(device, config),
     }
-  } 
+  }
 }
 
+// Texture with the size, format and usage described by the config, standing in for a surface
+#[cfg(not(target_arch = "wasm32"))]
+fn create_offscreen_texture(device: &Device, config: &SurfaceConfiguration) -> Texture {
+  device.create_texture(&TextureDescriptor {
+    label: Some("Offscreen target"),
+    usage: config.usage,
+    size: Extent3d { width: config.width, height: config.height, depth_or_array_layers: 1 },
+    dimension: TextureDimension::D2,
+    format: config.format,
+    mip_level_count: 1,
+    sample_count: 1,
+  })
+}
 
-const VERTS: &[Vertex] = &[
-  Vertex { position: [-0.5,  0.5, 0.0], color: [1., 0.0, 0.0], texture_coords: [0., 1.] },
-  Vertex { position: [-0.5, -0.5, 0.0], color: [0.0, 1., 0.0], texture_coords: [0., 0.]  },
-  Vertex { position: [ 0.5, -0.5, 0.0], color: [0.0, 0.0, 1.], texture_coords: [1., 0.]  },
+async fn request_device(adapter: &Adapter, limits: &Limits) -> Result<(Device, Queue), RequestDeviceError> {
+  // Can be used for API call tracing, if that feature is
+  // enabled in wgpu-core
+  let trace = None; 
+
+  adapter.request_device(
+    &DeviceDescriptor {
+      features: Features::empty(),
+      // max_compute_workgroups_per_dimension: 0 was problematic
+      limits: limits.clone(),
+      label: Some("Root device"),
+    },
+    trace
+  ).await
+}
 
-  Vertex { position: [-0.5,  0.5, 0.0], color: [1., 0.0, 0.0], texture_coords: [0., 1.]  },
-  Vertex { position: [ 0.5, -0.5, 0.0], color: [0.0, 0.0, 1.], texture_coords: [1., 0.]  },
-  Vertex { position: [ 0.5,  0.5, 0.0], color: [0.0, 0.0, 1.], texture_coords: [1., 1.]  },
-]; 
+// What a model url turned out to contain
+enum LoadedModel {
+  Obj(Model),
+  Gltf(GltfScene),
+}
+
+// Creates GPU resources for models and textures on the state's device, see State::loader
+struct Loader {
+  device: Rc<Device>,
+  queue: Rc<Queue>,
+  samplers: Rc<RefCell<SamplerCache>>,
+  mipmaps: Rc<RefCell<MipmapGenerator>>,
+  material_layout: Rc<BindGroupLayout>,
+}
+
+impl Loader {
+  // Picks the loader from the file extension
+  async fn load_model(&self, url: &str) -> Result<LoadedModel, ModelError> {
+    let lower = url.to_lowercase();
+
+    if lower.ends_with(".gltf") || lower.ends_with(".glb") {
+      let gltf = gltf_import::load_gltf(&self.device, &self.queue, &self.samplers, &self.mipmaps, &self.material_layout, url).await?;
+
+      Ok(LoadedModel::Gltf(gltf))
+    } else {
+      let model = model::load_obj(&self.device, &self.queue, &self.samplers, &self.mipmaps, &self.material_layout, url).await?;
+
+      Ok(LoadedModel::Obj(model))
+    }
+  }
+
+  #[cfg(target_arch = "wasm32")]
+  async fn load_texture(&self, url: &str) -> Result<TextureResource, TextureError> {
+    let options = TextureOptions { mipmaps: true, ..Default::default() };
+
+    TextureResource::from_url(&self.device, &self.queue, &self.samplers, &self.mipmaps, url, url, &options).await
+  }
+}
 
 struct State {
-  surface: Surface,
-  device: Device,
-  queue: Queue,
+  target: RenderTarget,
+  // Shared with loaders, which create resources while the state keeps rendering
+  device: Rc<Device>,
+  queue: Rc<Queue>,
   config: SurfaceConfiguration,
   size: PhysicalSize<u32>,
+  // The depth compare function is baked into the pipeline, so it's rebuilt whenever the
+  // camera's depth mode changes
   render_pipeline: RenderPipeline,
-  vertex_buffer: Buffer,
-  vertex_count: u32,
-
-  diffuse_bind_group: BindGroup,
+  render_pipeline_layout: PipelineLayout,
+  pipeline_depth_mode: DepthMode,
+  shader: ShaderModule,
+  scene: Scene,
+  // Model the default quad was added as, its texture can be replaced with set_quad_texture
+  #[cfg(target_arch = "wasm32")]
+  quad: usize,
+  quad_node: NodeId,
+  material_layout: Rc<BindGroupLayout>,
+  model_layout: BindGroupLayout,
+  clear_color: Color,
+  identity_instance: InstanceBuffer,
+
+  depth_texture: TextureResource,
+  // Shared with loaders. Never borrowed across an await, so concurrent loads can't collide
+  samplers: Rc<RefCell<SamplerCache>>,
+  mipmaps: Rc<RefCell<MipmapGenerator>>,
 
   camera: Camera,
+  // The camera as of the previous simulation step, rendering blends from it towards camera
+  prev_camera: Camera,
+  camera_controller: CameraController,
   camera_uniform: CameraUniform,
   camera_buf: Buffer,
-  camera_bind_group: BindGroup
+  camera_bind_group: BindGroup,
+
+  clock: FrameClock,
+  frame_uniform: FrameUniform,
+  frame_buf: Buffer,
+  frame_bind_group: BindGroup,
+
+  // Keeps the device shared with other windows alive, dropped last since the resources above
+  // belong to it. Headless states own their device outright
+  context: Option<Rc<GpuContext>>,
 }
 
 impl State {
-  async fn new(window: &Window) -> Self {
+  async fn new(window: &Window, renderer: &RendererConfig) -> Result<Self, RendererError> {
     let size = window.inner_size();
 
-    let instance = Instance::new(Backends::all());
-    let surface = unsafe { instance.create_surface(window) };
-
-    // Adapter is a handle to graphics card driver
-    let adapter = instance.request_adapter(
-      &RequestAdapterOptions {
-        power_preference: PowerPreference::HighPerformance,
-        compatible_surface: Some(&surface),
-        // If `true`, force wgpu to pick an adapter that will work on all hardware.
-        // This usually means that the rendering backend will use a "software" system,
-        // instead of hardware such as a GPU.
-        force_fallback_adapter: false
-      }, 
-    ).await.expect("Unable to create surface");
-
-
-    // Can be used for API call tracing, if that feature is
-    // enabled in wgpu-core
-    let trace = None; 
-
-    let (device, queue) = adapter.request_device(
-      &DeviceDescriptor {
-        features: Features::empty(),
-        // max_compute_workgroups_per_dimension: 0 was problematic
-        limits: Limits::downlevel_webgl2_defaults(),
-        label: Some("Root device"),
-      },
-      trace
-    ).await.expect("Failed to query device");
-
-    // Log all available features
-    console::log_1(&JsValue::from(format!("Features \n    {:?}", adapter.features()))); 
-    console::log_1(&JsValue::from(format!("{:#?}", adapter.limits())));
+    let (context, surface) = GpuContext::with_surface(window, renderer).await?;
 
     // This will define how the surface creates its underlying SurfaceTextures.
     let config = SurfaceConfiguration {
       usage: TextureUsages::RENDER_ATTACHMENT,
-      format: surface.get_preferred_format(&adapter).expect("Unable to get preferred format"),
+      format: surface.get_preferred_format(&context.adapter).ok_or(RendererError::UnsupportedSurface)?,
       width: size.width,
       height: size.height,
-      present_mode: PresentMode::Fifo
+      present_mode: renderer.present_mode
     };
 
-    surface.configure(&device, &config);
+    surface.configure(&context.device, &config);
 
-    let diffuse_bytes = include_bytes!("happy.png");
-    let diffuse_resource = TextureResource::from_bytes(&device, &queue, diffuse_bytes, "diffuse-texture");
-    // let diffuse_resource = TextureResource::from_url(&device, &queue, "./happy.png", "diffuse-texture")
-    //     .await.expect("Get diffuse resource"); 
+    let target = RenderTarget::Surface(surface);
+    let mut state = Self::with_target(context.device.clone(), context.queue.clone(), target, config, renderer)?;
 
-    let texture_bind_group_layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
-      label: Some("Diffuse texture bind group"), 
-      entries: &[
-        BindGroupLayoutEntry {
-          binding: 0,
-          visibility: ShaderStages::FRAGMENT,
-          ty: BindingType::Texture {
-            sample_type: TextureSampleType::Float { filterable: true },
-            view_dimension: TextureViewDimension::D2,
-            multisampled: false
-          },
-          count: None,
-        },
-        BindGroupLayoutEntry {
-          binding: 1,
-          visibility: ShaderStages::FRAGMENT,
-          ty: BindingType::Sampler(SamplerBindingType::Filtering),
-          count: None
-        }
-      ] 
-    });
+    state.context = Some(context);
+    Ok(state)
+  }
 
-    let diffuse_bind_group = device.create_bind_group({
-      &BindGroupDescriptor {
-        label: Some("Diffuse bind group"),
-        layout: &texture_bind_group_layout, 
-        entries: &[
-          BindGroupEntry {
-            binding: 0,
-            resource: BindingResource::TextureView(&diffuse_resource.view)
-          },
-          BindGroupEntry {
-            binding: 1,
-            resource: BindingResource::Sampler(&diffuse_resource.sampler)
-          }
-        ]
-      }
-    }); 
-    
+  // Shared between windowed and headless rendering, the config describes the size and format of
+  // the target, which must already be configured
+  fn with_target(device: Rc<Device>, queue: Rc<Queue>, target: RenderTarget, config: SurfaceConfiguration, renderer: &RendererConfig) -> Result<Self, TextureError> {
+    let size = PhysicalSize::new(config.width, config.height);
+    let mut samplers = SamplerCache::new();
+    let mut mipmaps = MipmapGenerator::new(&device);
+    let depth_texture = TextureResource::create_depth_texture(&device, &mut samplers, &config, "depth-texture")?;
+
+    let diffuse_bytes = include_bytes!("happy.png");
+    let diffuse_resource = TextureResource::from_bytes(&device, &queue, &mut samplers, &mut mipmaps, diffuse_bytes, "diffuse-texture", &TextureOptions {
+      mipmaps: true,
+      ..Default::default()
+    })?;
+
+    let material_layout = Rc::new(Material::bind_group_layout(&device));
+    let model_layout = Scene::bind_group_layout(&device);
+    let diffuse_material = Material::new(&device, &material_layout, "diffuse", diffuse_resource, [1.; 4]);
 
     let module = device.create_shader_module(&ShaderModuleDescriptor {
       label: Some("Main shader"),
-      source: ShaderSource::Wgsl(include_str!("shader.wgsl").into())
+      source: ShaderSource::Wgsl(wgsl::source(include_str!("shader.wgsl")))
     });
 
-    let camera = Camera::new(config.width as f32 / config.height as f32);
+    let camera = Camera { depth_mode: renderer.depth_mode, ..Camera::new(config.width as f32 / config.height as f32) };
+    let mut camera_controller = CameraController::new(CameraMode::Orbit);
+
+    camera_controller.orbit.rotate_speed = renderer.orbit_rotate_speed;
+    camera_controller.orbit.zoom_speed = renderer.orbit_zoom_speed;
+    camera_controller.fly.speed = renderer.fly_speed;
+    camera_controller.fly.sensitivity = renderer.fly_sensitivity;
+
     let mut camera_uniform = CameraUniform::new();
 
     camera_uniform.update(&camera);
@@ -231,36 +312,82 @@ impl State {
       ]
     });
 
+    let frame_uniform = FrameUniform::default();
+    let frame_buf = device.create_buffer_init(&BufferInitDescriptor {
+      label: Some("Frame buf"),
+      contents: cast_slice(&[frame_uniform]),
+      usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST
+    });
+
+    let frame_layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+      label: Some("Frame bind group layout"),
+      entries: &[
+        BindGroupLayoutEntry {
+          count: None,
+          binding: 0,
+          visibility: ShaderStages::VERTEX | ShaderStages::FRAGMENT,
+          ty: BindingType::Buffer {
+            ty: BufferBindingType::Uniform,
+            has_dynamic_offset: false,
+            min_binding_size: None
+          }
+        }
+      ]
+    });
+
+    let frame_bind_group = device.create_bind_group(&BindGroupDescriptor {
+      label: Some("Frame bind group"),
+      layout: &frame_layout,
+      entries: &[
+        BindGroupEntry {
+          binding: 0,
+          resource: frame_buf.as_entire_binding()
+        }
+      ]
+    });
+
     let render_pipeline_layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
       label: Some("Render pipeline layout"),
       bind_group_layouts: &[
-        &texture_bind_group_layout,
+        &material_layout,
         &camera_layout,
+        &model_layout,
+        &frame_layout,
       ],
       push_constant_ranges: &[]
     });
 
-    let vertex_buffer = device.create_buffer_init(&util::BufferInitDescriptor {
-      label: Some("Vertex Buffer"),
-      usage: BufferUsages::VERTEX,
-      // Bytemuck provides utilities for safe-ishly bitfiddling structs 
-      contents: bytemuck::cast_slice(VERTS), 
-    }); 
+    let mut scene = Scene::new();
+    let quad = scene.add_model(Model {
+      meshes: vec![Mesh::new(&device, "Quad", VERTS, Indices::U16(INDICES))],
+      materials: vec![diffuse_material],
+    });
+    let quad_node = scene.add_node(None, "Quad", Transform::default());
+
+    scene.set_drawable(&device, &model_layout, quad_node, quad, 0..1);
+
+    let identity_instance = InstanceBuffer::identity(&device);
 
-    let render_pipeline = device.create_render_pipeline(&RenderPipelineDescriptor {
+    let render_pipeline = Self::create_render_pipeline(&device, &render_pipeline_layout, &module, config.format, camera.depth_mode);
+
+    Ok(Self { target, device, queue, config, size, render_pipeline, render_pipeline_layout, pipeline_depth_mode: camera.depth_mode, shader: module, scene, #[cfg(target_arch = "wasm32")] quad, quad_node, material_layout, model_layout, clear_color: renderer.clear_color, identity_instance, depth_texture, samplers: Rc::new(RefCell::new(samplers)), mipmaps: Rc::new(RefCell::new(mipmaps)), camera, prev_camera: camera, camera_controller, camera_bind_group, camera_buf, camera_uniform, clock: FrameClock::new(SIMULATION_STEP), frame_uniform, frame_buf, frame_bind_group, context: None })
+  }
+
+  fn create_render_pipeline(device: &Device, layout: &PipelineLayout, module: &ShaderModule, format: TextureFormat, depth_mode: DepthMode) -> RenderPipeline {
+    device.create_render_pipeline(&RenderPipelineDescriptor {
       label: Some("Render pipeline"),
-      layout: Some(&render_pipeline_layout),
+      layout: Some(layout),
       vertex: VertexState {
-        module: &module,
+        module,
         entry_point: "vs_main",
-        buffers: &[Vertex::desc()]
+        buffers: &[Vertex::desc(), InstanceData::desc()]
       },
       fragment: Some(FragmentState {
         entry_point: "fs_main",
-        module: &module,
+        module,
         targets: &[
           ColorTargetState {
-            format: config.format,
+            format,
             blend: Some(BlendState::REPLACE),
             write_mask: ColorWrites::ALL
           }
@@ -277,43 +404,182 @@ impl State {
         ..Default::default()
       },
       multisample: MultisampleState { count: 1, mask: !0, alpha_to_coverage_enabled: false },
-      depth_stencil: None,
+      depth_stencil: Some(DepthStencilState {
+        format: TextureResource::DEPTH_FORMAT,
+        depth_write_enabled: true,
+        // Must agree with the clear value in render
+        depth_compare: depth_mode.compare(),
+        stencil: StencilState::default(),
+        bias: DepthBiasState::default()
+      }),
       multiview: None
-    });
-
-    let vertex_count = VERTS.len() as u32;
-
-
-    Self { surface, device, queue, config, size, render_pipeline, vertex_buffer, vertex_count, diffuse_bind_group, camera, camera_bind_group, camera_buf, camera_uniform }
+    })
   }
 
   fn resize(&mut self, new_size: PhysicalSize<u32>) {
     if new_size.width > 0 && new_size.height > 0 {
+      let mut config = self.config.clone();
+
+      config.width = new_size.width;
+      config.height = new_size.height;
+
+      // Validates the new size against the device limits, so check it before touching the surface
+      self.depth_texture = match TextureResource::create_depth_texture(&self.device, &mut self.samplers.borrow_mut(), &config, "depth-texture") {
+        Ok(depth_texture) => depth_texture,
+        Err(e) => {
+          log::error!("Unable to resize to {}x{}: {}", new_size.width, new_size.height, e);
+          return;
+        }
+      };
+
       self.size = new_size;
-      self.config.width = new_size.width;
-      self.config.height = new_size.height;
-      self.surface.configure(&self.device, &self.config); 
+      self.config = config;
+      self.target.configure(&self.device, &self.config); 
+
+      self.camera.resize(new_size.width, new_size.height);
+      self.camera_uniform.update(&self.camera);
+      self.queue.write_buffer(&self.camera_buf, 0, cast_slice(&[self.camera_uniform]));
+    }
+  }
+
+  // Configures the target again at its current size
+  fn reconfigure(&mut self) {
+    self.target.configure(&self.device, &self.config);
+  }
+
+  // Shares what loading needs, so the state isn't borrowed while resources are fetched
+  fn loader(&self) -> Loader {
+    Loader {
+      device: self.device.clone(),
+      queue: self.queue.clone(),
+      samplers: self.samplers.clone(),
+      mipmaps: self.mipmaps.clone(),
+      material_layout: self.material_layout.clone(),
     }
   }
 
-  fn input(&mut self, _event: &WindowEvent) -> bool {
-    false
+  async fn load_model(&mut self, url: &str) -> Result<Vec<NodeId>, ModelError> {
+    let loaded = self.loader().load_model(url).await?;
+
+    Ok(self.add_model(url, loaded))
+  }
+
+  // Adds the model at the root of the scene, returning the nodes it was added as
+  fn add_model(&mut self, url: &str, loaded: LoadedModel) -> Vec<NodeId> {
+    match loaded {
+      LoadedModel::Obj(model) => {
+        let mesh_count = model.meshes.len();
+        let model = self.scene.add_model(model);
+        let node = self.scene.add_node(None, url, Transform::default());
+
+        self.scene.set_drawable(&self.device, &self.model_layout, node, model, 0..mesh_count);
+        vec![node]
+      }
+      // Looks through the file's first camera, if it has one. Its depth mode is kept, e.g.
+      // infinite perspective cameras need reverse-z, and the pipeline follows it on the next frame
+      LoadedModel::Gltf(mut gltf) => {
+        if !gltf.cameras.is_empty() {
+          let camera = gltf.cameras.swap_remove(0);
+
+          self.camera = Camera { aspect: self.camera.aspect, ..camera };
+          self.prev_camera = self.camera;
+        }
+
+        self.scene.add_gltf(&self.device, &self.model_layout, gltf, None)
+      }
+    }
+  }
+
+  // See Scene::set_instances
+  fn set_instances(&mut self, node: NodeId, instances: &[InstanceData]) -> bool {
+    self.scene.set_instances(&self.device, &self.queue, node, instances)
+  }
+
+  // Replaces the texture shown on the default quad
+  #[cfg(target_arch = "wasm32")]
+  fn set_quad_texture(&mut self, texture: TextureResource) {
+    let material = Material::new(&self.device, &self.material_layout, "diffuse", texture, [1.; 4]);
+
+    self.scene.models[self.quad].materials[0] = material;
+  }
+
+  // Moves the camera without interpolating from where it was
+  #[cfg(target_arch = "wasm32")]
+  fn set_camera_pose(&mut self, eye: Point3<f32>, target: Point3<f32>) {
+    self.camera.eye = eye;
+    self.camera.target = target;
+    self.prev_camera = self.camera;
+  }
+
+  fn input(&mut self, event: &WindowEvent) -> bool {
+    self.camera_controller.process_event(event)
+  }
+
+  fn mouse_motion(&mut self, dx: f64, dy: f64) {
+    self.camera_controller.process_mouse_motion(dx, dy);
+  }
+
+  fn toggle_camera_mode(&mut self) -> CameraMode {
+    self.camera_controller.toggle();
+    self.camera_controller.mode()
   }
   
+  // Advances the simulation by however many fixed steps fit in the time since the last frame,
+  // then prepares the uniforms for rendering in between the last two steps
   fn update(&mut self) {
-    todo!()
+    let time = self.clock.tick();
+
+    for _ in 0..time.steps {
+      self.step(self.clock.step);
+    }
+
+    self.frame_uniform.update(&time);
+    self.queue.write_buffer(&self.frame_buf, 0, cast_slice(&[self.frame_uniform]));
+
+    self.camera_uniform.update(&self.prev_camera.lerp(&self.camera, time.alpha));
+    self.queue.write_buffer(&self.camera_buf, 0, cast_slice(&[self.camera_uniform]));
+    self.scene.update(&self.queue);
+  }
+
+  // Prepares the uniforms for rendering the scene and camera as they are, without advancing the
+  // simulation. Used for single frames that must not depend on timing
+  #[cfg(not(target_arch = "wasm32"))]
+  fn update_still(&mut self) {
+    self.prev_camera = self.camera;
+    self.camera_uniform.update(&self.camera);
+    self.queue.write_buffer(&self.camera_buf, 0, cast_slice(&[self.camera_uniform]));
+    self.scene.update(&self.queue);
+  }
+
+  fn step(&mut self, dt: Duration) {
+    self.prev_camera = self.camera;
+    self.camera_controller.update_camera(&mut self.camera, dt);
   }
   
   fn render(&mut self) -> Result<(), SurfaceError> {
     // The get_current_texture function will wait for the surface to provide
     // a new SurfaceTexture that we will render to.
-    let output = self.surface.get_current_texture()?;
-    let mut desc = TextureViewDescriptor::default();
-
-    desc.label = Some("Main output view"); 
+    let desc = TextureViewDescriptor {
+      label: Some("Main output view"),
+      ..Default::default()
+    };
 
     // We need to do this because we want to control how the render code interacts with the texture.
-    let view = output.texture.create_view(&desc);
+    let (output, view) = match &self.target {
+      RenderTarget::Surface(surface) => {
+        let output = surface.get_current_texture()?;
+        let view = output.texture.create_view(&desc);
+
+        (Some(output), view)
+      }
+      #[cfg(not(target_arch = "wasm32"))]
+      RenderTarget::Offscreen(texture) => (None, texture.create_view(&desc)),
+    };
+
+    if self.camera.depth_mode != self.pipeline_depth_mode {
+      self.render_pipeline = Self::create_render_pipeline(&self.device, &self.render_pipeline_layout, &self.shader, self.config.format, self.camera.depth_mode);
+      self.pipeline_depth_mode = self.camera.depth_mode;
+    }
 
     // The encoder builds a command buffer that we can then send to the gpu
     let mut encoder = self.device.create_command_encoder(&CommandEncoderDescriptor {
@@ -321,28 +587,36 @@ impl State {
     });
 
     // Clear color attachment
-    let color_clear = Color { r: 1., g: 0., b: 0., a: 1. };
     let color_attachment = RenderPassColorAttachment {
       view: &view, // Texture to save to 
       resolve_target: None,
       ops: Operations {
-        load: LoadOp::Clear(color_clear),
+        load: LoadOp::Clear(self.clear_color),
         store: true
       }
     };
 
+    let depth_attachment = RenderPassDepthStencilAttachment {
+      view: &self.depth_texture.view,
+      depth_ops: Some(Operations {
+        load: LoadOp::Clear(self.camera.depth_mode.clear_value()),
+        store: true
+      }),
+      stencil_ops: None
+    };
+
     // Clear the screen
     let mut render_pass = encoder.begin_render_pass(&RenderPassDescriptor {
       label: Some("Render pass"),
-      depth_stencil_attachment: None, 
+      depth_stencil_attachment: Some(depth_attachment), 
       color_attachments: &[color_attachment]
     });
 
     render_pass.set_pipeline(&self.render_pipeline);
-    render_pass.set_bind_group(0, &self.diffuse_bind_group, &[]);
     render_pass.set_bind_group(1, &self.camera_bind_group, &[]);
-    render_pass.set_vertex_buffer(0, self.vertex_buffer.slice(..)); 
-    render_pass.draw(0..self.vertex_count, 0..1); 
+    render_pass.set_bind_group(3, &self.frame_bind_group, &[]);
+
+    self.scene.draw(&mut render_pass, &self.identity_instance);
 
     // By storing render_pass, we perform a mutable borrow of the encoder. In order to call 
     // encoder.finish() (also mutable), we need to drop the reference
@@ -350,21 +624,64 @@ impl State {
 
     self.queue.submit(std::iter::once(encoder.finish())); 
 
-    output.present();
+    // Offscreen frames are read back with State::capture instead
+    if let Some(output) = output {
+      output.present();
+    }
 
     Ok(())
   }
 }
 
 
-fn init_logger() {
-  std::panic::set_hook(Box::new(console_error_panic_hook::hook));
-  console_log::init_with_level(log::Level::Warn).expect("Could't initialize logger");
+#[cfg(target_arch = "wasm32")]
+// Every viewer on the page calls this, the logger can only be set once
+fn init_logger(level: log::LevelFilter) {
+  static INIT: std::sync::Once = std::sync::Once::new();
+
+  INIT.call_once(|| {
+    std::panic::set_hook(Box::new(console_error_panic_hook::hook));
+
+    // Off has no corresponding level, leave the logger unset instead. A logger the page's own
+    // wasm code installed is kept
+    if let Some(level) = level.to_level() {
+      console_log::init_with_level(level).ok();
+    }
+  });
+}
+
+// Override with e.g. RUST_LOG=wexp=debug. An application embedding us may have installed its
+// own logger already, which is kept
+#[cfg(not(target_arch = "wasm32"))]
+fn init_logger(level: log::LevelFilter) {
+  let filter = level.to_string().to_lowercase();
+
+  env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(filter)).try_init().ok();
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn init_window(event_loop: &EventLoop<()>, config: &RendererConfig) -> Result<Window, Box<dyn Error>> {
+  let window = WindowBuilder::new()
+    .with_title("wexp")
+    .with_inner_size(PhysicalSize::new(config.width, config.height))
+    .build(event_loop)?;
+
+  Ok(window)
 }
-fn init_window(event_loop: &EventLoop<()>) -> Result<Window, Box<dyn Error>> {
-  let window = WindowBuilder::new().build(&event_loop)?;
 
-  window.set_inner_size(PhysicalSize::new(450, 400));
+// Mounted canvases come with a resizer, which has to live as long as the window
+#[cfg(target_arch = "wasm32")]
+fn init_window(event_loop: &EventLoop<()>, mount: Mount, config: &RendererConfig) -> Result<(Window, Option<CanvasResizer>), Box<dyn Error>> {
+  if let Some(canvas) = mount.canvas()? {
+    let window = canvas::preserving_layout(&canvas, || WindowBuilder::new().with_canvas(Some(canvas.clone())).build(event_loop))?;
+    let resizer = CanvasResizer::new(&window.canvas())?;
+
+    return Ok((window, Some(resizer)));
+  }
+
+  let window = WindowBuilder::new().build(event_loop)?;
+
+  window.set_inner_size(PhysicalSize::new(config.width, config.height));
   
   let web_window = web_sys::window().ok_or("No window found")?;
   let web_document = web_window.document().ok_or("No document found")?;
@@ -373,23 +690,10 @@ fn init_window(event_loop: &EventLoop<()>) -> Result<Window, Box<dyn Error>> {
 
   web_body.append_child(&web_canvas).map_err(|_| "Failed to append canvas to document body")?; 
   
-  return Ok(window); 
+  Ok((window, None))
 }
 
 
-// fn kill_window() -> Result<(), Box<dyn Error>> {
-//   console::log_1(&"Kill window!".into());
-
-//   let web_window = web_sys::window().ok_or("No window found")?;
-//   let web_document = web_window.document().ok_or("No document found")?;
-//   let web_body = web_document.body().ok_or("No body found")?;
-//   let child = web_body.first_child().ok_or("No child found")?;
-  
-//   web_body.remove_child(&child).map_err(|_| "Failed to remove child")?; 
-
-//   Ok(())
-// }
-
 // fn request_animation_frame(f: &Closure<dyn FnMut()>) {
 //   web_sys::window().unwrap()
 //     .request_animation_frame(f.as_ref().unchecked_ref())
@@ -413,69 +717,206 @@ fn init_window(event_loop: &EventLoop<()>) -> Result<Window, Box<dyn Error>> {
 //   }
 // }
 
+// Configured through the page's query string, e.g. `index.html?clearColor=0,0,0`
+#[cfg(target_arch = "wasm32")]
 #[wasm_bindgen(start)]
-pub async fn run() {
-  console::log_1(&JsValue::from("Run")); 
+pub async fn run() -> Result<(), JsValue> {
+  let query = web_sys::window().and_then(|window| window.location().search().ok()).unwrap_or_default();
+  let config = RendererConfig::from_query(&query).unwrap_or_else(|e| {
+    web_sys::console::error_1(&e.to_string().into());
+    RendererConfig::default()
+  });
+
+  let result = run_with_mount(Mount::Body, config).await;
+
+  if let Err(RendererError::NoAdapter(report)) = &result {
+    if report.suggests_webgl2() && switch_to_webgl2(&query).await {
+      return Ok(());
+    }
+  }
 
-  init_logger();
+  // The default page has nothing else to show, so explain what went wrong in its place
+  if let Err(e) = &result {
+    show_error(&e.to_string());
+  }
 
-  console::log_1(&"Creating event loop!".into());
+  result.map_err(JsValue::from)
+}
 
-  let event_loop = EventLoop::new(); 
-  let window = init_window(&event_loop).expect("Unable to create window");
+// Where the WebGL2 build of the default page is served, see Trunk.toml
+#[cfg(target_arch = "wasm32")]
+const WEBGL2_PAGE: &str = "webgl2/";
+
+// Sends the browser to the WebGL2 build, keeping the query. Checks the page is there first, so
+// a deployment without it shows the error rather than a missing page
+#[cfg(target_arch = "wasm32")]
+async fn switch_to_webgl2(query: &str) -> bool {
+  if let Err(e) = resources::load_bytes(WEBGL2_PAGE).await {
+    log::warn!("No WebGL2 build to switch to: {}", e);
+    return false;
+  }
 
-  console::log_1(&"Creating state!".into());
+  log::info!("No WebGPU adapter, switching to the WebGL2 build");
 
-  let mut state = State::new(&window).await; 
+  web_sys::window()
+    .map(|window| window.location().replace(&format!("{}{}", WEBGL2_PAGE, query)).is_ok())
+    .unwrap_or(false)
+}
 
-  console::log_1(&"Initializing event loop!".into());
+#[cfg(target_arch = "wasm32")]
+pub async fn run_with_mount(mount: Mount, config: RendererConfig) -> Result<(), RendererError> {
+  start(mount, config, &[]).await
+}
 
-  // let f = Rc::new(RefCell::new(None));
-  // let g = f.clone(); 
-  
-  // *g.borrow_mut() = Some(Closure::wrap(
-  //     Box::new(move || {
-  //         console::log_1(&"Initializing event loop!".into());
-  
-  //         request_animation_frame(f.borrow().as_ref().unwrap()); 
-  //     }) as Box<dyn FnMut()>
-  // )); 
-  
-  // request_animation_frame(g.borrow().as_ref().unwrap()); 
-  
-  event_loop.run(move |event, _, _control_flow| {
+#[cfg(target_arch = "wasm32")]
+fn show_error(message: &str) {
+  let document = web_sys::window().and_then(|window| window.document());
+  let body = document.as_ref().and_then(|document| document.body());
+
+  if let (Some(document), Some(body)) = (document, body) {
+    if let Ok(paragraph) = document.create_element("p") {
+      paragraph.set_text_content(Some(message));
+      body.append_child(&paragraph).ok();
+    }
+  }
+}
+
+// Renders into an existing canvas, given either as the element or its id. The canvas is sized
+// by the page's CSS, e.g. `runInCanvas("viewer")` for `<canvas id="viewer">`
+#[cfg(target_arch = "wasm32")]
+#[wasm_bindgen(js_name = runInCanvas)]
+pub async fn run_in_canvas(canvas: JsValue, config: config::RendererConfigInit) -> Result<(), JsValue> {
+  let config = RendererConfig::from_js(&config)?;
+
+  run_with_mount(Mount::from(canvas), config).await?;
+
+  Ok(())
+}
+
+// Blocks on creating the State, then runs the event loop until the window is closed. Returns
+// only if the renderer couldn't be created, e.g. without a usable adapter
+#[cfg(not(target_arch = "wasm32"))]
+pub fn run() -> Result<(), RendererError> {
+  run_with_models(&[])
+}
+
+// Same as run, additionally showing the obj or glTF models at the given paths
+#[cfg(not(target_arch = "wasm32"))]
+pub fn run_with_models(urls: &[String]) -> Result<(), RendererError> {
+  run_with_config(RendererConfig::default(), urls)
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub fn run_with_config(config: RendererConfig, model_urls: &[String]) -> Result<(), RendererError> {
+  pollster::block_on(start(config, model_urls))
+}
+
+// A window and the state rendering into it, driven by the event loop. Dropping the view releases
+// its GPU resources and, on the web, removes the canvas if we created it
+struct View {
+  // Declared before the window, so the surface is dropped before the window it presents to
+  state: State,
+  window: Window,
+  // Keeps a mounted canvas sized to its CSS box
+  #[cfg(target_arch = "wasm32")]
+  resizer: Option<CanvasResizer>,
+}
+
+// Mounted canvases belong to the page and are left in place, only the one appended to the body
+// by init_window is removed
+#[cfg(target_arch = "wasm32")]
+impl Drop for View {
+  fn drop(&mut self) {
+    if self.resizer.is_none() {
+      self.window.canvas().remove();
+    }
+  }
+}
+
+impl View {
+  fn handle_event(&mut self, event: Event<()>, control_flow: &mut ControlFlow) {
     // *control_flow = ControlFlow::Poll;
     // console::log_1(&"Fire ev".into()); 
     // *control_flow = ControlFlow::Exit; 
     log::debug!("{:?}", event);
 
-    // kill_window().expect("End it");
-
     if let Event::RedrawRequested(window_id) = event {
-      if window_id != window.id() { return; }
+      if window_id != self.window.id() { return; }
 
       // console::log_1(&"Redraw requested!".into());
 
-      // state.update();
-      match state.render() {
+      self.state.update();
+      match self.state.render() {
         Ok(_) => {},
-        Err(e) => eprintln!("{:?}", e)
+        // The surface no longer matches the window, e.g. it was moved to another display or the
+        // context was lost, so configure it again and pick up on the next frame
+        Err(e @ (SurfaceError::Lost | SurfaceError::Outdated)) => {
+          log::warn!("{:?} surface, reconfiguring", e);
+          self.state.reconfigure();
+        }
+        Err(SurfaceError::Timeout) => log::warn!("Timed out waiting for the surface, skipping frame"),
+        Err(SurfaceError::OutOfMemory) => {
+          log::error!("Out of memory, shutting down");
+          *control_flow = ControlFlow::Exit;
+        }
       }
     }
 
     if let Event::MainEventsCleared = event {
+      // CanvasResizer only resizes the canvas' drawing buffer, which winit doesn't report
+      #[cfg(target_arch = "wasm32")]
+      if self.resizer.is_some() && self.window.inner_size() != self.state.size {
+        self.state.resize(self.window.inner_size());
+      }
+
       // RedrawRequested will only trigger once, unless we manually
       // request it.
-      window.request_redraw(); 
+      self.window.request_redraw(); 
+    }
+    
+    if let Event::DeviceEvent { event: DeviceEvent::MouseMotion { delta: (dx, dy) }, .. } = event {
+      self.state.mouse_motion(dx, dy); 
     }
     
     if let Event::WindowEvent { ref event, window_id } = event {
-      if window_id != window.id() { return; }
+      if window_id != self.window.id() { return; }
+
+      if self.state.input(event) { return; }
       
       match event {
+        WindowEvent::KeyboardInput {
+          input: KeyboardInput {
+            state: ElementState::Pressed,
+            virtual_keycode: Some(VirtualKeyCode::Tab),
+            ..
+          }, ..
+        } => {
+          // Fly mode looks around with the pointer locked to the canvas
+          let grab = matches!(self.state.toggle_camera_mode(), CameraMode::Fly);
+
+          self.window.set_cursor_visible(!grab);
+          if let Err(e) = self.window.set_cursor_grab(grab) {
+            log::warn!("Unable to grab cursor: {:?}", e);
+          }
+        }
+        WindowEvent::KeyboardInput {
+          input: KeyboardInput {
+            state: ElementState::Pressed,
+            virtual_keycode: Some(VirtualKeyCode::P),
+            ..
+          }, ..
+        } => {
+          self.state.camera.toggle_projection(); 
+        }
+        WindowEvent::MouseInput { state: ElementState::Pressed, .. } => {
+          // The browser releases pointer lock on escape, so re-acquire it on click
+          if let CameraMode::Fly = self.state.camera_controller.mode() {
+            self.window.set_cursor_grab(true).ok(); 
+          }
+        }
         WindowEvent::Resized(size) => {
           log::debug!("Resizing window");
-          state.resize(*size); 
+          self.state.resize(*size); 
         }
         WindowEvent::CloseRequested |
         WindowEvent::KeyboardInput {
@@ -485,13 +926,74 @@ pub async fn run() {
             ..
           }, ..
         } => {
-          console::log_1(&"EXIT!".into()); 
-          // *control_flow = ControlFlow::Exit
+          // Ending the loop drops the view along with its resources. On the web, escape also
+          // releases pointer lock, so viewers are only torn down through Viewer.destroy
+          if cfg!(not(target_arch = "wasm32")) {
+            *control_flow = ControlFlow::Exit
+          }
         },
         _ => {}    
       }
     }
-  });
+  }
+}
+
+// Only returns if the renderer couldn't be created, the event loop runs for good otherwise
+async fn start(#[cfg(target_arch = "wasm32")] mount: Mount, config: RendererConfig, model_urls: &[String]) -> Result<(), RendererError> {
+  init_logger(config.log_level);
+
+  log::debug!("Creating event loop!");
+
+  let event_loop = EventLoop::new(); 
+  #[cfg(target_arch = "wasm32")]
+  let (window, resizer) = init_window(&event_loop, mount, &config).map_err(RendererError::Window)?;
+  #[cfg(not(target_arch = "wasm32"))]
+  let window = init_window(&event_loop, &config).map_err(RendererError::Window)?;
+
+  log::debug!("Creating state!");
+
+  let mut state = match State::new(&window, &config).await {
+    Ok(state) => state,
+    Err(e) => {
+      // Nothing will ever be drawn, don't leave an empty canvas behind
+      #[cfg(target_arch = "wasm32")]
+      if resizer.is_none() {
+        window.canvas().remove();
+      }
+
+      return Err(e);
+    }
+  };
+
+  for url in model_urls {
+    if let Err(e) = state.load_model(url).await {
+      log::error!("Unable to load {}: {}", url, e);
+    }
+  }
+
+  log::debug!("Initializing event loop!");
+
+  // let f = Rc::new(RefCell::new(None));
+  // let g = f.clone(); 
+  
+  // *g.borrow_mut() = Some(Closure::wrap(
+  //     Box::new(move || {
+  //         log::debug!("Initializing event loop!");
+  
+  //         request_animation_frame(f.borrow().as_ref().unwrap()); 
+  //     }) as Box<dyn FnMut()>
+  // )); 
+  
+  // request_animation_frame(g.borrow().as_ref().unwrap()); 
+  
+  let mut view = View {
+    window,
+    state,
+    #[cfg(target_arch = "wasm32")]
+    resizer,
+  };
+
+  event_loop.run(move |event, _, control_flow| view.handle_event(event, control_flow));
 
   // window.set_cursor_visible(true); 
 
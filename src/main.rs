@@ -1,21 +1,52 @@
+#[cfg(target_arch = "wasm32")]
 use wasm_bindgen::{JsValue, prelude::wasm_bindgen};
+#[cfg(target_arch = "wasm32")]
 use web_sys::console;
+#[cfg(target_arch = "wasm32")]
 use wexp::run;
 // use wasm_bindgen::prelude::wasm_bindgen;
 // use console_error_panic_hook::set_once as set_panic_hook;
 
+#[cfg(target_arch = "wasm32")]
 #[cfg_attr(target_arch="wasm32", wasm_bindgen(start))]
 #[wasm_bindgen(inline_js = "export function snippetTest() { console.log('Hello from JS FFI!'); }")]
 extern "C" {
     fn snippetTest();
 }
 
+#[cfg(target_arch = "wasm32")]
 pub fn main() {
     // set_panic_hook();
-    
-    snippetTest(); 
-    console::log_1(&JsValue::from("Before run")); 
+
+    snippetTest();
+    console::log_1(&JsValue::from("Before run"));
 
     run();
-    console::log_1(&JsValue::from("After run")); 
+    console::log_1(&JsValue::from("After run"));
+}
+
+// Opens a desktop window (X11 or Wayland) showing the obj files passed as arguments and blocks
+// until it is closed. Alternatively `wexp --headless <path> [width] [height]` renders a single
+// frame into an image file
+#[cfg(not(target_arch = "wasm32"))]
+pub fn main() {
+    let args: Vec<String> = std::env::args().collect();
+
+    if args.get(1).map(String::as_str) != Some("--headless") {
+        if let Err(e) = wexp::run_with_models(&args[1..]) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+
+        return;
+    }
+
+    let path = args.get(2).map(String::as_str).unwrap_or("wexp.png");
+    let width = args.get(3).and_then(|width| width.parse().ok()).unwrap_or(450);
+    let height = args.get(4).and_then(|height| height.parse().ok()).unwrap_or(400);
+
+    if let Err(e) = wexp::headless::render_to_file(path, width, height) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
 }
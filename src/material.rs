@@ -0,0 +1,112 @@
+use bytemuck::{Pod, Zeroable, cast_slice};
+use wgpu::util::{BufferInitDescriptor, DeviceExt};
+use wgpu::*;
+
+use crate::texture_resource::TextureResource;
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+pub struct MaterialUniform {
+  // Multiplied with the diffuse texture
+  pub base_color: [f32; 4],
+  pub metallic: f32,
+  pub roughness: f32,
+  // Uniform structs are padded to 16 bytes
+  pub _padding: [f32; 2],
+}
+
+impl MaterialUniform {
+  // Fully metallic and rough, the glTF defaults
+  pub fn new(base_color: [f32; 4]) -> Self {
+    Self { base_color, metallic: 1., roughness: 1., _padding: [0.; 2] }
+  }
+}
+
+// Diffuse texture and base color for a set of meshes, bound to group 0. The metallic-roughness
+// and normal textures are kept with the material but not bound yet, the shader is unlit
+pub struct Material {
+  pub name: String,
+  pub diffuse_texture: TextureResource,
+  pub metallic_roughness_texture: Option<TextureResource>,
+  pub normal_texture: Option<TextureResource>,
+  pub uniform: MaterialUniform,
+  pub buffer: Buffer,
+  pub bind_group: BindGroup,
+}
+
+impl Material {
+  pub fn bind_group_layout(device: &Device) -> BindGroupLayout {
+    device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+      label: Some("Material bind group layout"), 
+      entries: &[
+        BindGroupLayoutEntry {
+          binding: 0,
+          visibility: ShaderStages::FRAGMENT,
+          ty: BindingType::Texture {
+            sample_type: TextureSampleType::Float { filterable: true },
+            view_dimension: TextureViewDimension::D2,
+            multisampled: false
+          },
+          count: None,
+        },
+        BindGroupLayoutEntry {
+          binding: 1,
+          visibility: ShaderStages::FRAGMENT,
+          ty: BindingType::Sampler(SamplerBindingType::Filtering),
+          count: None
+        },
+        BindGroupLayoutEntry {
+          binding: 2,
+          visibility: ShaderStages::FRAGMENT,
+          ty: BindingType::Buffer {
+            ty: BufferBindingType::Uniform,
+            has_dynamic_offset: false,
+            min_binding_size: None
+          },
+          count: None
+        }
+      ] 
+    })
+  }
+
+  pub fn new(device: &Device, layout: &BindGroupLayout, name: &str, diffuse_texture: TextureResource, base_color: [f32; 4]) -> Self {
+    Self::from_uniform(device, layout, name, diffuse_texture, MaterialUniform::new(base_color))
+  }
+
+  pub fn from_uniform(device: &Device, layout: &BindGroupLayout, name: &str, diffuse_texture: TextureResource, uniform: MaterialUniform) -> Self {
+    let buffer = device.create_buffer_init(&BufferInitDescriptor {
+      label: Some(&format!("{} material buf", name)),
+      contents: cast_slice(&[uniform]),
+      usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST
+    });
+
+    let bind_group = device.create_bind_group(&BindGroupDescriptor {
+      label: Some(&format!("{} material bind group", name)),
+      layout, 
+      entries: &[
+        BindGroupEntry {
+          binding: 0,
+          resource: BindingResource::TextureView(&diffuse_texture.view)
+        },
+        BindGroupEntry {
+          binding: 1,
+          resource: BindingResource::Sampler(&diffuse_texture.sampler)
+        },
+        BindGroupEntry {
+          binding: 2,
+          resource: buffer.as_entire_binding()
+        }
+      ]
+    });
+
+    Self {
+      name: name.to_string(),
+      diffuse_texture,
+      metallic_roughness_texture: None,
+      normal_texture: None,
+      uniform,
+      buffer,
+      bind_group
+    }
+  }
+}
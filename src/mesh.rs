@@ -0,0 +1,144 @@
+use std::mem;
+use std::ops::Range;
+use bytemuck::{Pod, Zeroable, cast_slice};
+use wgpu::util::{BufferInitDescriptor, DeviceExt};
+use wgpu::*;
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+pub struct Vertex {
+  pub position: [f32; 3],
+  pub color: [f32; 3],
+  pub texture_coords: [f32; 2],
+  pub normal: [f32; 3],
+  // xyz points along +u in tangent space, w is the handedness of the bitangent
+  pub tangent: [f32; 4],
+}
+
+impl Vertex {
+  // Workaround for rust bug? 
+  const LAYOUT: [VertexAttribute; 5] =
+    vertex_attr_array![0 => Float32x3, 1 => Float32x3, 2 => Float32x2, 3 => Float32x3, 4 => Float32x4]; 
+  
+  pub fn desc<'a>() -> VertexBufferLayout<'a> {
+    let array_stride = mem::size_of::<Vertex>() as BufferAddress;
+    
+    // VertexBufferLayout {
+    //     array_stride,
+    //     step_mode: VertexStepMode::Vertex,
+    //     attributes: &[
+    //         VertexAttribute {
+    //             shader_location: 0, 
+    //             format: VertexFormat::Float32x3, 
+    //             offset: 0,
+    //         },
+    //         VertexAttribute {
+    //             shader_location: 1, 
+    //             format: VertexFormat::Float32x3, 
+    //             offset: mem::size_of::<[f32; 3]>() as BufferAddress,
+    //         }
+    //     ]
+    // }
+
+    VertexBufferLayout {
+      array_stride,
+      step_mode: VertexStepMode::Vertex,
+      attributes: &Self::LAYOUT
+    }
+  } 
+}
+
+// Index data in either width. Prefer U16 where the vertex count allows, it halves the size
+// of the index buffer
+pub enum Indices<'a> {
+  U16(&'a [u16]),
+  U32(&'a [u32]),
+}
+
+impl Indices<'_> {
+  pub fn format(&self) -> IndexFormat {
+    match self {
+      Indices::U16(_) => IndexFormat::Uint16,
+      Indices::U32(_) => IndexFormat::Uint32,
+    }
+  }
+
+  pub fn len(&self) -> usize {
+    match self {
+      Indices::U16(indices) => indices.len(),
+      Indices::U32(indices) => indices.len(),
+    }
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.len() == 0
+  }
+
+  fn bytes(&self) -> &[u8] {
+    match self {
+      Indices::U16(indices) => cast_slice(indices),
+      Indices::U32(indices) => cast_slice(indices),
+    }
+  }
+}
+
+// Indexed triangle list in the Vertex layout
+pub struct Mesh {
+  pub name: String,
+  pub vertex_buffer: Buffer,
+  pub index_buffer: Buffer,
+  pub index_format: IndexFormat,
+  pub index_count: u32,
+  // Index into the owning Model's materials
+  pub material: usize,
+}
+
+impl Mesh {
+  pub fn new(device: &Device, name: &str, vertices: &[Vertex], indices: Indices) -> Self {
+    let vertex_buffer = device.create_buffer_init(&BufferInitDescriptor {
+      label: Some(&format!("{} vertex buffer", name)),
+      usage: BufferUsages::VERTEX,
+      // Bytemuck provides utilities for safe-ishly bitfiddling structs 
+      contents: cast_slice(vertices), 
+    });
+
+    // Buffer sizes must be a multiple of 4 (COPY_BUFFER_ALIGNMENT), so pad an odd number of
+    // u16 indices with an extra one that is never drawn
+    let mut index_bytes = indices.bytes().to_vec();
+
+    index_bytes.resize(index_bytes.len().next_multiple_of(COPY_BUFFER_ALIGNMENT as usize), 0);
+
+    let index_buffer = device.create_buffer_init(&BufferInitDescriptor {
+      label: Some(&format!("{} index buffer", name)),
+      usage: BufferUsages::INDEX,
+      contents: &index_bytes,
+    });
+
+    Self {
+      name: name.to_string(),
+      vertex_buffer,
+      index_buffer,
+      index_format: indices.format(),
+      index_count: indices.len() as u32,
+      material: 0,
+    }
+  }
+
+  // For loaders, whose index data is u32. Narrowed to u16 when every index fits
+  pub fn with_u32_indices(device: &Device, name: &str, vertices: &[Vertex], indices: &[u32]) -> Self {
+    if vertices.len() <= u16::MAX as usize {
+      let indices: Vec<u16> = indices.iter().map(|index| *index as u16).collect();
+
+      Self::new(device, name, vertices, Indices::U16(&indices))
+    } else {
+      Self::new(device, name, vertices, Indices::U32(indices))
+    }
+  }
+
+  // Instance data is read from vertex buffer slot 1, which must be bound by the caller
+  pub fn draw_instanced<'a>(&'a self, render_pass: &mut RenderPass<'a>, instances: Range<u32>) {
+    render_pass.set_vertex_buffer(0, self.vertex_buffer.slice(..));
+    render_pass.set_index_buffer(self.index_buffer.slice(..), self.index_format);
+    render_pass.draw_indexed(0..self.index_count, 0, instances);
+  }
+}
@@ -0,0 +1,151 @@
+use std::collections::HashMap;
+use wgpu::*;
+
+use crate::wgsl;
+
+// Number of levels in a full mip chain, halving the largest dimension down to 1x1
+pub fn mip_level_count(width: u32, height: u32) -> u32 {
+  32 - width.max(height).max(1).leading_zeros()
+}
+
+// A blit pipeline for one texture format
+struct Blit {
+  pipeline: RenderPipeline,
+  bind_group_layout: BindGroupLayout,
+}
+
+// Fills mip levels 1..mip_level_count by rendering each level from the one above it with a
+// linear filter. WebGL2 can only sample and render whole textures, so each level is rendered
+// into a texture of its own and then copied into place. The shader and a pipeline per texture
+// format are created once and reused, on WebGL2 each pipeline is a program link
+pub struct MipmapGenerator {
+  module: ShaderModule,
+  sampler: Sampler,
+  blits: HashMap<TextureFormat, Blit>,
+}
+
+impl MipmapGenerator {
+  pub fn new(device: &Device) -> Self {
+    let module = device.create_shader_module(&ShaderModuleDescriptor {
+      label: Some("Blit shader"),
+      source: ShaderSource::Wgsl(wgsl::source(include_str!("blit.wgsl")))
+    });
+
+    let sampler = device.create_sampler(&SamplerDescriptor {
+      label: Some("Mipmap sampler"),
+      address_mode_u: AddressMode::ClampToEdge,
+      address_mode_v: AddressMode::ClampToEdge,
+      address_mode_w: AddressMode::ClampToEdge,
+      mag_filter: FilterMode::Linear,
+      min_filter: FilterMode::Linear,
+      ..Default::default()
+    });
+
+    Self { module, sampler, blits: HashMap::new() }
+  }
+
+  // The texture must have been created with TEXTURE_BINDING and COPY_DST usage, and with the
+  // full mip chain for its size
+  pub fn generate(&mut self, device: &Device, queue: &Queue, texture: &Texture, format: TextureFormat, width: u32, height: u32) {
+    let mip_level_count = mip_level_count(width, height);
+
+    if mip_level_count < 2 {
+      return;
+    }
+
+    let Self { module, sampler, blits } = self;
+    let Blit { pipeline, bind_group_layout } = blits.entry(format).or_insert_with(|| create_blit(device, module, format));
+    let mut encoder = device.create_command_encoder(&CommandEncoderDescriptor {
+      label: Some("Mipmap encoder"),
+    });
+
+    // Kept alive until the encoder is submitted
+    let mut levels: Vec<Texture> = Vec::with_capacity(mip_level_count as usize - 1);
+
+    for mip in 1..mip_level_count {
+      let size = Extent3d { width: (width >> mip).max(1), height: (height >> mip).max(1), depth_or_array_layers: 1 };
+      let level = device.create_texture(&TextureDescriptor {
+        label: Some("Mipmap level"),
+        usage: TextureUsages::RENDER_ATTACHMENT | TextureUsages::TEXTURE_BINDING | TextureUsages::COPY_SRC,
+        size,
+        dimension: TextureDimension::D2,
+        format,
+        mip_level_count: 1,
+        sample_count: 1,
+      });
+
+      let source = levels.last().unwrap_or(texture).create_view(&TextureViewDescriptor::default());
+      let target = level.create_view(&TextureViewDescriptor::default());
+      let bind_group = device.create_bind_group(&BindGroupDescriptor {
+        label: Some("Mipmap bind group"),
+        layout: bind_group_layout,
+        entries: &[
+          BindGroupEntry {
+            binding: 0,
+            resource: BindingResource::TextureView(&source)
+          },
+          BindGroupEntry {
+            binding: 1,
+            resource: BindingResource::Sampler(sampler)
+          }
+        ]
+      });
+
+      {
+        let mut render_pass = encoder.begin_render_pass(&RenderPassDescriptor {
+          label: Some("Mipmap pass"),
+          depth_stencil_attachment: None,
+          color_attachments: &[RenderPassColorAttachment {
+            view: &target,
+            resolve_target: None,
+            ops: Operations {
+              load: LoadOp::Clear(Color::TRANSPARENT),
+              store: true
+            }
+          }]
+        });
+
+        render_pass.set_pipeline(pipeline);
+        render_pass.set_bind_group(0, &bind_group, &[]);
+        render_pass.draw(0..3, 0..1);
+      }
+
+      encoder.copy_texture_to_texture(level.as_image_copy(), ImageCopyTexture {
+        texture,
+        mip_level: mip,
+        origin: Origin3d::ZERO,
+        aspect: TextureAspect::All
+      }, size);
+
+      levels.push(level);
+    }
+
+    queue.submit(std::iter::once(encoder.finish()));
+  }
+}
+
+fn create_blit(device: &Device, module: &ShaderModule, format: TextureFormat) -> Blit {
+  let pipeline = device.create_render_pipeline(&RenderPipelineDescriptor {
+    label: Some("Mipmap pipeline"),
+    // Let wgpu derive the bind group layout from the shader
+    layout: None,
+    vertex: VertexState {
+      module,
+      entry_point: "vs_main",
+      buffers: &[]
+    },
+    fragment: Some(FragmentState {
+      module,
+      entry_point: "fs_main",
+      targets: &[format.into()]
+    }),
+    primitive: PrimitiveState::default(),
+    multisample: MultisampleState::default(),
+    depth_stencil: None,
+    multiview: None
+  });
+
+  let bind_group_layout = pipeline.get_bind_group_layout(0);
+
+  Blit { pipeline, bind_group_layout }
+}
@@ -0,0 +1,198 @@
+use std::cell::RefCell;
+use std::error::Error;
+use std::fmt;
+use std::io::{BufReader, Cursor};
+use std::ops::Range;
+use wgpu::*;
+
+use crate::material::Material;
+use crate::mesh::{Mesh, Vertex};
+use crate::resources::{self, ResourceError};
+use crate::mipmap::MipmapGenerator;
+use crate::sampler::SamplerCache;
+use crate::texture_resource::{TextureError, TextureOptions, TextureResource};
+
+#[derive(Debug)]
+pub enum ModelError {
+  Load(ResourceError),
+  Obj(tobj::LoadError),
+  Gltf(gltf::Error),
+  // The glTF parsed but references data we can't use, e.g. a malformed data uri
+  InvalidGltf(String),
+  Texture(TextureError),
+}
+
+impl fmt::Display for ModelError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      ModelError::Load(e) => e.fmt(f),
+      ModelError::Obj(e) => write!(f, "Failed to parse obj: {}", e),
+      ModelError::Gltf(e) => write!(f, "Failed to parse gltf: {}", e),
+      ModelError::InvalidGltf(message) => write!(f, "Invalid gltf: {}", message),
+      ModelError::Texture(e) => e.fmt(f),
+    }
+  }
+}
+
+impl Error for ModelError {
+  fn source(&self) -> Option<&(dyn Error + 'static)> {
+    match self {
+      ModelError::Load(e) => Some(e),
+      ModelError::Obj(e) => Some(e),
+      ModelError::Gltf(e) => Some(e),
+      ModelError::InvalidGltf(_) => None,
+      ModelError::Texture(e) => Some(e),
+    }
+  }
+}
+
+impl From<ResourceError> for ModelError {
+  fn from(e: ResourceError) -> Self {
+    ModelError::Load(e)
+  }
+}
+
+impl From<tobj::LoadError> for ModelError {
+  fn from(e: tobj::LoadError) -> Self {
+    ModelError::Obj(e)
+  }
+}
+
+impl From<gltf::Error> for ModelError {
+  fn from(e: gltf::Error) -> Self {
+    ModelError::Gltf(e)
+  }
+}
+
+impl From<TextureError> for ModelError {
+  fn from(e: TextureError) -> Self {
+    ModelError::Texture(e)
+  }
+}
+
+// A set of meshes and the materials they reference. Every mesh's material index is valid
+pub struct Model {
+  pub meshes: Vec<Mesh>,
+  pub materials: Vec<Material>,
+}
+
+impl Model {
+  // For loaders. Meshes are paired with the index of their material, those without one or with
+  // an index out of range are drawn with a plain white material that is added if needed
+  pub fn with_default_material(device: &Device, queue: &Queue, samplers: &mut SamplerCache, layout: &BindGroupLayout, meshes: Vec<(Mesh, Option<usize>)>, mut materials: Vec<Material>) -> Result<Self, TextureError> {
+    let default_material = materials.len();
+    let mut needs_default_material = false;
+
+    let meshes = meshes.into_iter().map(|(mut mesh, material)| {
+      mesh.material = match material {
+        Some(material) if material < default_material => material,
+        _ => {
+          needs_default_material = true;
+          default_material
+        }
+      };
+
+      mesh
+    }).collect();
+
+    if needs_default_material {
+      let white = TextureResource::from_color(device, queue, samplers, [255; 4], "default")?;
+
+      materials.push(Material::new(device, layout, "default", white, [1.; 4]));
+    }
+
+    Ok(Self { meshes, materials })
+  }
+
+  pub fn draw_meshes<'a>(&'a self, render_pass: &mut RenderPass<'a>, meshes: Range<usize>, instances: Range<u32>) {
+    for mesh in &self.meshes[meshes] {
+      render_pass.set_bind_group(0, &self.materials[mesh.material].bind_group, &[]);
+      mesh.draw_instanced(render_pass, instances.clone());
+    }
+  }
+}
+
+// Loads an obj file along with its mtl files and diffuse textures, which are resolved
+// relative to the obj url
+pub async fn load_obj(device: &Device, queue: &Queue, samplers: &RefCell<SamplerCache>, mipmaps: &RefCell<MipmapGenerator>, layout: &BindGroupLayout, url: &str) -> Result<Model, ModelError> {
+  let bytes = resources::load_bytes(url).await?;
+  let (obj_models, obj_materials) = tobj::load_obj_buf_async(
+    &mut BufReader::new(Cursor::new(bytes)),
+    &tobj::GPU_LOAD_OPTIONS,
+    |mtl_path| async move {
+      let mtl_url = resources::resolve_url(url, &mtl_path);
+      let mtl_bytes = resources::load_bytes(&mtl_url).await.map_err(|e| {
+        log::warn!("{}", e);
+        tobj::LoadError::OpenFileFailed
+      })?;
+
+      tobj::load_mtl_buf(&mut BufReader::new(Cursor::new(mtl_bytes)))
+    }
+  ).await?;
+
+  // A missing mtl file shouldn't prevent us from showing the geometry
+  let obj_materials = obj_materials.unwrap_or_else(|e| {
+    log::warn!("Unable to load materials for {}: {}", url, e);
+    Vec::new()
+  });
+
+  let mut materials = Vec::with_capacity(obj_materials.len() + 1);
+
+  for obj_material in obj_materials {
+    let [r, g, b] = obj_material.diffuse;
+    let mut color = [r, g, b, obj_material.dissolve];
+    let diffuse_texture = if obj_material.diffuse_texture.is_empty() {
+      None
+    } else {
+      let texture_url = resources::resolve_url(url, &obj_material.diffuse_texture);
+      let options = TextureOptions { mipmaps: true, ..Default::default() };
+
+      // Like a missing mtl file, a missing texture shouldn't prevent us from showing the geometry
+      match TextureResource::from_url(device, queue, samplers, mipmaps, &texture_url, &obj_material.name, &options).await {
+        Ok(texture) => Some(texture),
+        Err(e) => {
+          log::warn!("Unable to load texture for material {} of {}: {}", obj_material.name, url, e);
+          color = [1.; 4];
+          None
+        }
+      }
+    };
+    let diffuse_texture = match diffuse_texture {
+      Some(texture) => texture,
+      None => TextureResource::from_color(device, queue, &mut samplers.borrow_mut(), [255; 4], &obj_material.name)?,
+    };
+
+    materials.push(Material::new(device, layout, &obj_material.name, diffuse_texture, color));
+  }
+
+  // Meshes without a material are drawn plain white, as are those whose material's texture
+  // failed to load
+  let meshes = obj_models.into_iter().map(|obj_model| {
+    let obj_mesh = obj_model.mesh;
+    let vertices: Vec<Vertex> = (0..obj_mesh.positions.len() / 3).map(|i| Vertex {
+      position: [obj_mesh.positions[i * 3], obj_mesh.positions[i * 3 + 1], obj_mesh.positions[i * 3 + 2]],
+      color: if obj_mesh.vertex_color.is_empty() {
+        [1., 1., 1.]
+      } else {
+        [obj_mesh.vertex_color[i * 3], obj_mesh.vertex_color[i * 3 + 1], obj_mesh.vertex_color[i * 3 + 2]]
+      },
+      // Obj texture coordinates have their origin in the lower left, same as our vertex data
+      texture_coords: if obj_mesh.texcoords.is_empty() {
+        [0., 0.]
+      } else {
+        [obj_mesh.texcoords[i * 2], obj_mesh.texcoords[i * 2 + 1]]
+      },
+      normal: if obj_mesh.normals.is_empty() {
+        [0., 0., 1.]
+      } else {
+        [obj_mesh.normals[i * 3], obj_mesh.normals[i * 3 + 1], obj_mesh.normals[i * 3 + 2]]
+      },
+      // Obj has no tangents
+      tangent: [1., 0., 0., 1.],
+    }).collect();
+
+    (Mesh::with_u32_indices(device, &obj_model.name, &vertices, &obj_mesh.indices), obj_mesh.material_id)
+  }).collect();
+
+  Ok(Model::with_default_material(device, queue, &mut samplers.borrow_mut(), layout, meshes, materials)?)
+}
@@ -0,0 +1,84 @@
+use std::error::Error;
+use std::fmt;
+
+#[derive(Debug)]
+pub enum ResourceError {
+  // The request could not be made, or the server responded with an error status
+  Fetch(String),
+  Io(std::io::Error),
+}
+
+impl fmt::Display for ResourceError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      ResourceError::Fetch(message) => write!(f, "Failed to fetch resource: {}", message),
+      ResourceError::Io(e) => write!(f, "Failed to read resource: {}", e),
+    }
+  }
+}
+
+impl Error for ResourceError {
+  fn source(&self) -> Option<&(dyn Error + 'static)> {
+    match self {
+      ResourceError::Io(e) => Some(e),
+      _ => None
+    }
+  }
+}
+
+impl From<std::io::Error> for ResourceError {
+  fn from(e: std::io::Error) -> Self {
+    ResourceError::Io(e)
+  }
+}
+
+// Fetches the raw bytes at the given url, relative urls resolve against the page
+#[cfg(target_arch = "wasm32")]
+pub async fn load_bytes(url: &str) -> Result<Vec<u8>, ResourceError> {
+  use wasm_bindgen::{JsCast, JsValue};
+  use wasm_bindgen_futures::JsFuture;
+  use web_sys::{Request, RequestInit, RequestMode, Response};
+
+  let fetch_error = |e: JsValue| ResourceError::Fetch(format!("{}: {:?}", url, e));
+  let mut request_init = RequestInit::new();
+
+  request_init.method("GET");
+  request_init.mode(RequestMode::Cors);
+
+  let window = web_sys::window().ok_or_else(|| ResourceError::Fetch("No window found".into()))?;
+  let request = Request::new_with_str_and_init(url, &request_init).map_err(fetch_error)?;
+  let response: Response = JsFuture::from(window.fetch_with_request(&request)).await
+    .map_err(fetch_error)?
+    .dyn_into()
+    .map_err(fetch_error)?;
+
+  if !response.ok() {
+    return Err(ResourceError::Fetch(format!("{}: {} {}", url, response.status(), response.status_text())));
+  }
+
+  let buffer = JsFuture::from(response.array_buffer().map_err(fetch_error)?).await.map_err(fetch_error)?;
+
+  Ok(js_sys::Uint8Array::new(&buffer).to_vec())
+}
+
+// Reads the raw bytes at the given path, relative paths resolve against the working directory
+#[cfg(not(target_arch = "wasm32"))]
+pub async fn load_bytes(path: &str) -> Result<Vec<u8>, ResourceError> {
+  Ok(std::fs::read(path)?)
+}
+
+// Resolves a path referenced from within a resource, e.g. a texture named by a material file,
+// against the url of that resource
+pub fn resolve_url(base: &str, path: &str) -> String {
+  // Exporters on Windows like to write backslashes
+  let path = path.replace('\\', "/");
+
+  if path.contains("://") || path.starts_with('/') {
+    return path;
+  }
+
+  match base.rfind('/') {
+    Some(i) => format!("{}/{}", &base[..i], path),
+    None => path
+  }
+}
@@ -0,0 +1,139 @@
+use std::collections::HashMap;
+use std::num::NonZeroU8;
+use std::rc::Rc;
+use wgpu::*;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct SamplerOptions {
+  pub address_mode_u: AddressMode,
+  pub address_mode_v: AddressMode,
+  pub address_mode_w: AddressMode,
+  // The mag_filter and min_filter options describe what to do when a fragment covers multiple pixels,
+  // or there are multiple fragments for a single pixel
+  pub mag_filter: FilterMode,
+  pub min_filter: FilterMode,
+  // How to blend between mip levels, has no effect on textures without mipmaps
+  pub mipmap_filter: FilterMode,
+  pub lod_min_clamp: f32,
+  pub lod_max_clamp: f32,
+  // Makes this a comparison sampler, e.g. for sampling depth textures
+  pub compare: Option<CompareFunction>,
+  // Requires DownlevelFlags::ANISOTROPIC_FILTERING, valid values are 1, 2, 4, 8 and 16
+  pub anisotropy_clamp: Option<NonZeroU8>,
+}
+
+impl Default for SamplerOptions {
+  fn default() -> Self {
+    Self {
+      address_mode_u: AddressMode::ClampToEdge,
+      address_mode_v: AddressMode::ClampToEdge,
+      address_mode_w: AddressMode::ClampToEdge,
+      // Trilinear, so minified mipmapped textures blend within and between levels
+      mag_filter: FilterMode::Linear,
+      min_filter: FilterMode::Linear,
+      mipmap_filter: FilterMode::Linear,
+      lod_min_clamp: 0.,
+      lod_max_clamp: f32::MAX,
+      compare: None,
+      anisotropy_clamp: None,
+    }
+  }
+}
+
+impl SamplerOptions {
+  pub fn descriptor<'a>(&self, label: Option<&'a str>) -> SamplerDescriptor<'a> {
+    SamplerDescriptor {
+      label,
+      address_mode_u: self.address_mode_u,
+      address_mode_v: self.address_mode_v,
+      address_mode_w: self.address_mode_w,
+      mag_filter: self.mag_filter,
+      min_filter: self.min_filter,
+      mipmap_filter: self.mipmap_filter,
+      lod_min_clamp: self.lod_min_clamp,
+      lod_max_clamp: self.lod_max_clamp,
+      compare: self.compare,
+      anisotropy_clamp: self.anisotropy_clamp,
+      border_color: None,
+    }
+  }
+
+  // f32 isn't Hash, so the lod clamps are keyed on their bit patterns
+  fn key(&self) -> SamplerKey {
+    SamplerKey {
+      address_modes: [self.address_mode_u, self.address_mode_v, self.address_mode_w],
+      filters: [self.mag_filter, self.min_filter, self.mipmap_filter],
+      lod_clamps: [self.lod_min_clamp.to_bits(), self.lod_max_clamp.to_bits()],
+      compare: self.compare,
+      anisotropy_clamp: self.anisotropy_clamp,
+    }
+  }
+}
+
+#[derive(Debug, PartialEq, Eq, Hash)]
+struct SamplerKey {
+  address_modes: [AddressMode; 3],
+  filters: [FilterMode; 3],
+  lod_clamps: [u32; 2],
+  compare: Option<CompareFunction>,
+  anisotropy_clamp: Option<NonZeroU8>,
+}
+
+// Most textures are sampled the same way, so share one sampler object per distinct set of
+// options rather than creating one per texture
+#[derive(Default)]
+pub struct SamplerCache {
+  samplers: HashMap<SamplerKey, Rc<Sampler>>,
+}
+
+impl SamplerCache {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  pub fn get(&mut self, device: &Device, options: &SamplerOptions) -> Rc<Sampler> {
+    self.samplers.entry(options.key())
+      .or_insert_with(|| Rc::new(device.create_sampler(&options.descriptor(Some("Cached sampler")))))
+      .clone()
+  }
+
+  pub fn len(&self) -> usize {
+    self.samplers.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.samplers.is_empty()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn equal_options_share_a_key() {
+    let options = SamplerOptions { address_mode_u: AddressMode::Repeat, lod_max_clamp: 4., ..Default::default() };
+    let same = SamplerOptions { lod_max_clamp: 4., address_mode_u: AddressMode::Repeat, ..Default::default() };
+
+    assert_eq!(options.key(), same.key());
+    assert_eq!(SamplerOptions::default().key(), SamplerOptions::default().key());
+  }
+
+  #[test]
+  fn filters_trilinearly_by_default() {
+    let options = SamplerOptions::default();
+
+    assert_eq!([options.mag_filter, options.min_filter, options.mipmap_filter], [FilterMode::Linear; 3]);
+  }
+
+  #[test]
+  fn different_options_get_different_keys() {
+    let options = SamplerOptions::default();
+    let clamped = SamplerOptions { lod_max_clamp: 4., ..options };
+    let comparison = SamplerOptions { compare: Some(CompareFunction::LessEqual), ..options };
+
+    assert_ne!(options.key(), clamped.key());
+    assert_ne!(options.key(), comparison.key());
+    assert_ne!(comparison.key(), SamplerOptions { compare: Some(CompareFunction::Greater), ..options }.key());
+  }
+}
@@ -0,0 +1,377 @@
+use std::ops::Range;
+use bytemuck::{Pod, Zeroable, cast_slice};
+use cgmath::{Matrix4, One};
+use wgpu::util::{BufferInitDescriptor, DeviceExt};
+use wgpu::*;
+
+use crate::gltf_import::GltfScene;
+use crate::instance::{InstanceBuffer, InstanceData};
+use crate::model::Model;
+use crate::transform::Transform;
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+pub struct ModelUniform {
+  model_mat: [[f32; 4]; 4],
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct NodeId(usize);
+
+impl NodeId {
+  // Position in the order nodes were added, stable for the lifetime of the scene. Turned back
+  // into an id with Scene::node_id
+  pub fn index(&self) -> usize {
+    self.0
+  }
+}
+
+// What a node draws, a range of the meshes of one of the scene's models
+pub struct Drawable {
+  pub model: usize,
+  pub meshes: Range<usize>,
+  // Copies drawn in a single call, relative to the node. Drawn once when None
+  pub instances: Option<InstanceBuffer>,
+  buffer: Buffer,
+  bind_group: BindGroup,
+}
+
+pub struct Node {
+  pub name: String,
+  transform: Transform,
+  parent: Option<NodeId>,
+  children: Vec<NodeId>,
+  world: Matrix4<f32>,
+  // The local transform changed since the last update_transforms
+  dirty: bool,
+  // The world matrix changed since the last write_uniforms
+  uniform_dirty: bool,
+  drawable: Option<Drawable>,
+}
+
+impl Node {
+  pub fn transform(&self) -> &Transform {
+    &self.transform
+  }
+
+  pub fn parent(&self) -> Option<NodeId> {
+    self.parent
+  }
+
+  pub fn children(&self) -> &[NodeId] {
+    &self.children
+  }
+
+  // As of the last update_transforms
+  pub fn world(&self) -> Matrix4<f32> {
+    self.world
+  }
+
+  pub fn drawable(&self) -> Option<&Drawable> {
+    self.drawable.as_ref()
+  }
+}
+
+// Hierarchy of nodes, each positioned relative to its parent. Changing a transform only marks
+// the node dirty, world matrices are recomputed for dirty subtrees once per frame in update
+#[derive(Default)]
+pub struct Scene {
+  nodes: Vec<Node>,
+  roots: Vec<NodeId>,
+  pub models: Vec<Model>,
+}
+
+impl Scene {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  pub fn bind_group_layout(device: &Device) -> BindGroupLayout {
+    device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+      label: Some("Model bind group layout"),
+      entries: &[
+        BindGroupLayoutEntry {
+          binding: 0,
+          visibility: ShaderStages::VERTEX,
+          ty: BindingType::Buffer {
+            ty: BufferBindingType::Uniform,
+            has_dynamic_offset: false,
+            min_binding_size: None
+          },
+          count: None
+        }
+      ]
+    })
+  }
+
+  pub fn node(&self, id: NodeId) -> &Node {
+    &self.nodes[id.0]
+  }
+
+  // Nodes are never removed, so any index below the node count is valid
+  pub fn node_id(&self, index: usize) -> Option<NodeId> {
+    (index < self.nodes.len()).then_some(NodeId(index))
+  }
+
+  pub fn roots(&self) -> &[NodeId] {
+    &self.roots
+  }
+
+  pub fn add_node(&mut self, parent: Option<NodeId>, name: &str, transform: Transform) -> NodeId {
+    let id = NodeId(self.nodes.len());
+
+    self.nodes.push(Node {
+      name: name.to_string(),
+      transform,
+      parent,
+      children: Vec::new(),
+      world: Matrix4::one(),
+      dirty: true,
+      uniform_dirty: true,
+      drawable: None,
+    });
+
+    match parent {
+      Some(parent) => self.nodes[parent.0].children.push(id),
+      None => self.roots.push(id),
+    }
+
+    id
+  }
+
+  pub fn add_model(&mut self, model: Model) -> usize {
+    self.models.push(model);
+    self.models.len() - 1
+  }
+
+  // Draws the given meshes of a model at the node, replacing whatever it drew before
+  pub fn set_drawable(&mut self, device: &Device, layout: &BindGroupLayout, id: NodeId, model: usize, meshes: Range<usize>) {
+    let node = &mut self.nodes[id.0];
+    let buffer = device.create_buffer_init(&BufferInitDescriptor {
+      label: Some(&format!("{} model buf", node.name)),
+      contents: cast_slice(&[ModelUniform { model_mat: node.world.into() }]),
+      usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST
+    });
+
+    let bind_group = device.create_bind_group(&BindGroupDescriptor {
+      label: Some(&format!("{} model bind group", node.name)),
+      layout,
+      entries: &[
+        BindGroupEntry {
+          binding: 0,
+          resource: buffer.as_entire_binding()
+        }
+      ]
+    });
+
+    node.drawable = Some(Drawable { model, meshes, instances: None, buffer, bind_group });
+  }
+
+  // Draws the node's meshes once per instance, e.g. one marker per feature of a point layer.
+  // Nodes without a drawable have nothing to instance, so that is refused
+  pub fn set_instances(&mut self, device: &Device, queue: &Queue, id: NodeId, instances: &[InstanceData]) -> bool {
+    let node = &mut self.nodes[id.0];
+    let drawable = match node.drawable.as_mut() {
+      Some(drawable) => drawable,
+      None => return false,
+    };
+
+    match &mut drawable.instances {
+      Some(buffer) => buffer.update(device, queue, instances),
+      None => drawable.instances = Some(InstanceBuffer::new(device, &format!("{} instance buf", node.name), instances)),
+    }
+
+    true
+  }
+
+  // Marks the node dirty, so hand out the transform mutably only through here
+  pub fn transform_mut(&mut self, id: NodeId) -> &mut Transform {
+    let node = &mut self.nodes[id.0];
+
+    node.dirty = true;
+    &mut node.transform
+  }
+
+  pub fn set_transform(&mut self, id: NodeId, transform: Transform) {
+    *self.transform_mut(id) = transform;
+  }
+
+  // Moves the node, along with its children, under a new parent. Parenting a node to one of
+  // its own descendants would create a cycle, so that is refused
+  pub fn set_parent(&mut self, id: NodeId, parent: Option<NodeId>) -> bool {
+    let mut ancestor = parent;
+
+    while let Some(current) = ancestor {
+      if current == id {
+        return false;
+      }
+
+      ancestor = self.nodes[current.0].parent;
+    }
+
+    match self.nodes[id.0].parent {
+      Some(old) => self.nodes[old.0].children.retain(|child| *child != id),
+      None => self.roots.retain(|root| *root != id),
+    }
+
+    match parent {
+      Some(parent) => self.nodes[parent.0].children.push(id),
+      None => self.roots.push(id),
+    }
+
+    self.nodes[id.0].parent = parent;
+    self.nodes[id.0].dirty = true;
+
+    true
+  }
+
+  // Instantiates an imported glTF scene under the given parent, returning the new root nodes.
+  // Nodes are added once, references to a node that was already added (or doesn't exist) are
+  // skipped so a malformed hierarchy can't loop forever
+  pub fn add_gltf(&mut self, device: &Device, layout: &BindGroupLayout, gltf: GltfScene, parent: Option<NodeId>) -> Vec<NodeId> {
+    let model = self.add_model(gltf.model);
+    let mut roots = Vec::with_capacity(gltf.roots.len());
+    let mut added = vec![false; gltf.nodes.len()];
+    // Reversed so nodes are popped, and therefore added, in file order
+    let mut stack: Vec<(usize, Option<NodeId>)> = gltf.roots.iter().rev().map(|root| (*root, None)).collect();
+
+    while let Some((index, gltf_parent)) = stack.pop() {
+      if added.get(index) != Some(&false) {
+        log::warn!("Skipping glTF node {}, it is missing or already added", index);
+        continue;
+      }
+
+      added[index] = true;
+
+      let gltf_node = &gltf.nodes[index];
+      let id = self.add_node(gltf_parent.or(parent), &gltf_node.name, gltf_node.transform);
+
+      if !gltf_node.meshes.is_empty() {
+        self.set_drawable(device, layout, id, model, gltf_node.meshes.clone());
+      }
+
+      if gltf_parent.is_none() {
+        roots.push(id);
+      }
+
+      stack.extend(gltf_node.children.iter().rev().map(|child| (*child, Some(id))));
+    }
+
+    roots
+  }
+
+  // Recomputes the world matrix of every dirty node and everything below it
+  pub fn update_transforms(&mut self) {
+    let mut stack: Vec<(NodeId, Matrix4<f32>, bool)> = self.roots.iter()
+      .map(|root| (*root, Matrix4::one(), false))
+      .collect();
+
+    while let Some((id, parent_world, parent_changed)) = stack.pop() {
+      let node = &mut self.nodes[id.0];
+      let changed = node.dirty || parent_changed;
+
+      if changed {
+        node.world = parent_world * node.transform.matrix();
+        node.dirty = false;
+        node.uniform_dirty = true;
+      }
+
+      let world = node.world;
+
+      stack.extend(node.children.iter().map(|child| (*child, world, changed)));
+    }
+  }
+
+  // Uploads the world matrices that changed since the last call
+  pub fn write_uniforms(&mut self, queue: &Queue) {
+    for node in &mut self.nodes {
+      if let (true, Some(drawable)) = (node.uniform_dirty, &node.drawable) {
+        queue.write_buffer(&drawable.buffer, 0, cast_slice(&[ModelUniform { model_mat: node.world.into() }]));
+      }
+
+      node.uniform_dirty = false;
+    }
+  }
+
+  pub fn update(&mut self, queue: &Queue) {
+    self.update_transforms();
+    self.write_uniforms(queue);
+  }
+
+  // Expects the camera bind group at 1, the material and model matrix are bound to 0 and 2.
+  // Nodes without instances draw a single copy using the identity instance
+  pub fn draw<'a>(&'a self, render_pass: &mut RenderPass<'a>, identity: &'a InstanceBuffer) {
+    for node in &self.nodes {
+      if let Some(drawable) = &node.drawable {
+        let instances = drawable.instances.as_ref().unwrap_or(identity);
+
+        render_pass.set_bind_group(2, &drawable.bind_group, &[]);
+        render_pass.set_vertex_buffer(1, instances.buffer.slice(..));
+        self.models[drawable.model].draw_meshes(render_pass, drawable.meshes.clone(), 0..instances.count());
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use cgmath::{Point3, Transform as _, Vector3};
+
+  fn translation(x: f32, y: f32, z: f32) -> Transform {
+    Transform { translation: Vector3::new(x, y, z), ..Default::default() }
+  }
+
+  fn world_origin(scene: &Scene, id: NodeId) -> Point3<f32> {
+    scene.node(id).world().transform_point(Point3::new(0., 0., 0.))
+  }
+
+  #[test]
+  fn children_inherit_parent_transform() {
+    let mut scene = Scene::new();
+    let parent = scene.add_node(None, "parent", translation(1., 0., 0.));
+    let child = scene.add_node(Some(parent), "child", translation(0., 2., 0.));
+
+    scene.update_transforms();
+
+    assert_eq!(world_origin(&scene, parent), Point3::new(1., 0., 0.));
+    assert_eq!(world_origin(&scene, child), Point3::new(1., 2., 0.));
+    assert_eq!(scene.roots(), &[parent]);
+    assert_eq!(scene.node(parent).children(), &[child]);
+  }
+
+  #[test]
+  fn moving_parent_propagates_to_children() {
+    let mut scene = Scene::new();
+    let parent = scene.add_node(None, "parent", translation(1., 0., 0.));
+    let child = scene.add_node(Some(parent), "child", translation(0., 2., 0.));
+
+    scene.update_transforms();
+    scene.transform_mut(parent).translation.x = 5.;
+
+    // Nothing changes until the next update
+    assert_eq!(world_origin(&scene, child), Point3::new(1., 2., 0.));
+
+    scene.update_transforms();
+
+    assert_eq!(world_origin(&scene, child), Point3::new(5., 2., 0.));
+  }
+
+  #[test]
+  fn reparenting_keeps_hierarchy_acyclic() {
+    let mut scene = Scene::new();
+    let a = scene.add_node(None, "a", translation(1., 0., 0.));
+    let b = scene.add_node(Some(a), "b", translation(1., 0., 0.));
+    let c = scene.add_node(None, "c", translation(0., 0., 3.));
+
+    assert!(!scene.set_parent(a, Some(b)));
+    assert!(scene.set_parent(b, Some(c)));
+
+    scene.update_transforms();
+
+    assert_eq!(scene.roots(), &[a, c]);
+    assert!(scene.node(a).children().is_empty());
+    assert_eq!(scene.node(b).parent(), Some(c));
+    assert_eq!(world_origin(&scene, b), Point3::new(1., 0., 3.));
+  }
+}
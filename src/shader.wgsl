@@ -32,24 +32,62 @@ struct VertexInput {
   @location(0) position: vec3<f32>, 
   @location(1) color: vec3<f32>, 
   @location(2) texture_coords: vec2<f32>, 
+  @location(3) normal: vec3<f32>, 
+  @location(4) tangent: vec4<f32>, 
+};
+
+struct InstanceInput {
+  @location(5) model_mat_0: vec4<f32>, 
+  @location(6) model_mat_1: vec4<f32>, 
+  @location(7) model_mat_2: vec4<f32>, 
+  @location(8) model_mat_3: vec4<f32>, 
+  @location(9) color: vec4<f32>, 
 };
 
 struct VertexOutput {
   @builtin(position) clip_position: vec4<f32>, 
   @location(0)       color: vec3<f32>,
   @location(1)       texture_coords: vec2<f32>, 
+  @location(2)       instance_color: vec4<f32>, 
 };
 
 @group(1)
 @binding(0)
 var<uniform> camera: CameraUniform;
 
+struct ModelUniform {
+  model_mat: mat4x4<f32>,
+};
+
+@group(2)
+@binding(0)
+var<uniform> object: ModelUniform;
+
+// Seconds
+struct FrameUniform {
+  elapsed: f32,
+  delta: f32,
+  alpha: f32,
+  frame: u32,
+};
+
+@group(3)
+@binding(0)
+var<uniform> frame: FrameUniform;
+
 @stage(vertex)
-fn vs_main(model: VertexInput) -> VertexOutput {
+fn vs_main(model: VertexInput, instance: InstanceInput) -> VertexOutput {
   var out: VertexOutput;
+  let instance_mat = mat4x4<f32>(
+    instance.model_mat_0,
+    instance.model_mat_1,
+    instance.model_mat_2,
+    instance.model_mat_3
+  );
 
   out.color = model.color;
-  out.clip_position = camera.vp_mat * vec4<f32>(model.position, 1.0);
+  out.instance_color = instance.color;
+  out.clip_position = camera.vp_mat * object.model_mat * instance_mat * vec4<f32>(model.position, 1.0);
   out.texture_coords = model.texture_coords;
 
   // Flip, WGPU texture coordinate are like dxd, 1,1 lower right
@@ -67,8 +105,18 @@ var diffuse_texture : texture_2d<f32>;
 @binding(1)
 var diffuse_sampler : sampler; 
 
+struct MaterialUniform {
+  base_color: vec4<f32>,
+  metallic: f32,
+  roughness: f32,
+};
+
+@group(0)
+@binding(2)
+var<uniform> material: MaterialUniform;
+
 @stage(fragment)
 fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
     // return vec4<f32>(in.color, 1.0);
-  return textureSample(diffuse_texture, diffuse_sampler, in.texture_coords); 
+  return textureSample(diffuse_texture, diffuse_sampler, in.texture_coords) * material.base_color * in.instance_color; 
 }
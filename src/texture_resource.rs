@@ -1,38 +1,182 @@
+use std::cell::RefCell;
+use std::error::Error;
+use std::fmt;
+use std::rc::Rc;
 use image::DynamicImage;
 use wgpu::*;
 use core::num;
 
+use crate::mipmap::{self, MipmapGenerator};
+use crate::sampler::{SamplerCache, SamplerOptions};
+use crate::resources::{self, ResourceError};
+
+#[derive(Debug)]
+pub enum TextureError {
+  Load(ResourceError),
+  // The data looked like a supported image, but could not be decoded
+  Decode(image::ImageError),
+  // Not an image, or an image format we weren't built with (only png and jpeg are enabled)
+  UnsupportedFormat(String),
+  // Textures must have at least one pixel
+  Empty,
+  // Either dimension exceeds the device's max_texture_dimension_2d
+  TooLarge { width: u32, height: u32, max: u32 },
+}
+
+impl fmt::Display for TextureError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      TextureError::Load(e) => e.fmt(f),
+      TextureError::Decode(e) => write!(f, "Failed to decode image: {}", e),
+      TextureError::UnsupportedFormat(format) => write!(f, "Unsupported image format: {}", format),
+      TextureError::Empty => write!(f, "Texture has no pixels"),
+      TextureError::TooLarge { width, height, max } => {
+        write!(f, "Texture size {}x{} exceeds the device limit of {}", width, height, max)
+      }
+    }
+  }
+}
+
+impl Error for TextureError {
+  fn source(&self) -> Option<&(dyn Error + 'static)> {
+    match self {
+      TextureError::Load(e) => Some(e),
+      TextureError::Decode(e) => Some(e),
+      _ => None
+    }
+  }
+}
+
+impl From<ResourceError> for TextureError {
+  fn from(e: ResourceError) -> Self {
+    TextureError::Load(e)
+  }
+}
+
+impl From<image::ImageError> for TextureError {
+  fn from(e: image::ImageError) -> Self {
+    match e {
+      image::ImageError::Unsupported(e) => TextureError::UnsupportedFormat(e.to_string()),
+      e => TextureError::Decode(e)
+    }
+  }
+}
+
+pub fn decode(bytes: &[u8]) -> Result<DynamicImage, TextureError> {
+  Ok(image::load_from_memory(bytes)?)
+}
+
+// Validates a texture size against the device limits up front, wgpu would otherwise raise a
+// validation error that takes down the whole module
+pub fn check_size(width: u32, height: u32, limits: &Limits) -> Result<(), TextureError> {
+  let max = limits.max_texture_dimension_2d;
+
+  if width == 0 || height == 0 {
+    return Err(TextureError::Empty);
+  }
+
+  if width > max || height > max {
+    return Err(TextureError::TooLarge { width, height, max });
+  }
+
+  Ok(())
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+pub struct TextureOptions {
+  // Generate a full mip chain on the GPU, which avoids shimmering when the texture is minified
+  pub mipmaps: bool,
+  // Store the texels as is rather than as sRGB, for data such as normal or metallic-roughness maps
+  pub linear: bool,
+  pub sampler: SamplerOptions,
+}
+
+impl TextureOptions {
+  fn format(&self) -> TextureFormat {
+    if self.linear { TextureFormat::Rgba8Unorm } else { TextureFormat::Rgba8UnormSrgb }
+  }
+}
+
 pub struct TextureResource {
   pub texture: Texture,
   pub view : TextureView,
-  pub sampler: Sampler,
+  // Shared between textures with the same sampler options, see SamplerCache
+  pub sampler: Rc<Sampler>,
 }
 
 impl TextureResource {
+  pub const DEPTH_FORMAT: TextureFormat = TextureFormat::Depth32Float;
 
-  pub fn from_bytes(device: &Device, queue: &Queue, bytes: &[u8], label: &str) -> Self {
-    let img = image::load_from_memory(bytes).unwrap();
+  // Depth attachment matching the size of the surface. Needs to be recreated whenever the
+  // surface is resized
+  pub fn create_depth_texture(device: &Device, samplers: &mut SamplerCache, config: &SurfaceConfiguration, label: &str) -> Result<Self, TextureError> {
+    check_size(config.width, config.height, &device.limits())?;
 
-    Self::from_image(device, queue, &img, label)
+    let size = Extent3d { width: config.width, height: config.height, depth_or_array_layers: 1 };
+    let texture = device.create_texture(&TextureDescriptor {
+      label: Some(label),
+      // RENDER_ATTACHMENT so we can render depth into it, TEXTURE_BINDING so we can later
+      // sample it in shaders
+      usage: TextureUsages::RENDER_ATTACHMENT | TextureUsages::TEXTURE_BINDING,
+      size,
+      dimension: TextureDimension::D2,
+      format: Self::DEPTH_FORMAT,
+      mip_level_count: 1,
+      sample_count: 1,
+    });
+
+    let view = texture.create_view(&TextureViewDescriptor::default());
+    let sampler = samplers.get(device, &SamplerOptions {
+      mag_filter: FilterMode::Linear,
+      min_filter: FilterMode::Linear,
+      mipmap_filter: FilterMode::Nearest,
+      // Only used if we sample the depth texture directly, e.g. for shadows
+      compare: Some(CompareFunction::LessEqual),
+      ..Default::default()
+    });
+
+    Ok(TextureResource { texture, view, sampler })
+  }
+
+  pub fn from_bytes(device: &Device, queue: &Queue, samplers: &mut SamplerCache, mipmaps: &mut MipmapGenerator, bytes: &[u8], label: &str, options: &TextureOptions) -> Result<Self, TextureError> {
+    let img = decode(bytes)?;
+
+    Self::from_image(device, queue, samplers, mipmaps, &img, label, options)
   }
 
   // This approach brings in the image library which is a huge dependency. Currently we are missing
   // the WGPU method `queue.copyExternalImageToTexture` which would allow use to use ImageBitmaps directly
   // and handle this all in the browser
-  pub fn from_image(device: &Device, queue: &Queue, image: &DynamicImage, label: &str) -> Self {
-    let rgba = image.to_rgba8(); 
+  pub fn from_image(device: &Device, queue: &Queue, samplers: &mut SamplerCache, mipmaps: &mut MipmapGenerator, image: &DynamicImage, label: &str, options: &TextureOptions) -> Result<Self, TextureError> {
+    let resource = Self::upload(device, queue, samplers, image, label, options)?;
+
+    if options.mipmaps {
+      mipmaps.generate(device, queue, &resource.texture, options.format(), image.width(), image.height());
+    }
+
+    Ok(resource)
+  }
+
+  // Creates the texture and writes the image into its first mip level
+  fn upload(device: &Device, queue: &Queue, samplers: &mut SamplerCache, image: &DynamicImage, label: &str, options: &TextureOptions) -> Result<Self, TextureError> {
     let width = image.width();
     let height = image.height(); 
+
+    check_size(width, height, &device.limits())?;
+
+    let rgba = image.to_rgba8(); 
     let size = Extent3d { width, height, depth_or_array_layers: 1 };
+    let format = options.format();
+    let mip_level_count = if options.mipmaps { mipmap::mip_level_count(width, height) } else { 1 };
     let texture = device.create_texture(&TextureDescriptor {
       label: Some(label),
       // TEXTURE_BINDING tells wgpu that we want to use this texture in shaders
-      // COPY_DST means that we want to copy data to this texture
+      // COPY_DST means that we want to copy data to this texture, which includes its mip levels
       usage: TextureUsages::TEXTURE_BINDING | TextureUsages::COPY_DST,
       size,
       dimension: TextureDimension::D2,
-      format: TextureFormat::Rgba8UnormSrgb, 
-      mip_level_count: 1,
+      format, 
+      mip_level_count,
       sample_count: 1,
     });
 
@@ -50,104 +194,85 @@ impl TextureResource {
     };
 
     queue.write_texture(image_copy_texture, &rgba, layout, size); 
-    
+
     let view = texture.create_view(&TextureViewDescriptor::default());
-    let sampler = device.create_sampler(&SamplerDescriptor {
-      label: Some(label), 
-      // TEXTURE_BINDING tells wgpu that we want to use this texture in shaders
-      // COPY_DST means that we want to copy data to this texture
-      address_mode_u: AddressMode::ClampToEdge,
-      address_mode_v: AddressMode::ClampToEdge,
-      address_mode_w: AddressMode::ClampToEdge,
-      // The mag_filter and min_filter options describe what to do when a fragment covers multiple pixels,
-      // or there are multiple fragments for a single pixel
-      mag_filter: FilterMode::Linear,
-      min_filter: FilterMode::Nearest,
-      ..Default::default()
-    });
+    let sampler = samplers.get(device, &options.sampler);
 
-    TextureResource { texture, view, sampler }
+    Ok(TextureResource { texture, view, sampler })
   }
 
-  // copyExternalImageToTexture not implemented
+  // Single pixel texture, e.g. a stand in for materials without a texture. Has no mip chain to
+  // generate
+  pub fn from_color(device: &Device, queue: &Queue, samplers: &mut SamplerCache, color: [u8; 4], label: &str) -> Result<Self, TextureError> {
+    let image = DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(1, 1, image::Rgba(color)));
+
+    Self::upload(device, queue, samplers, &image, label, &TextureOptions::default())
+  }
+
+  // Fetches an image at runtime, over the network on the web and from disk on native.
+  // Decoding still goes through the image library, as wgpu is missing the
+  // `queue.copyExternalImageToTexture` method that would let us hand ImageBitmaps to the browser
   // https://github.com/toji/webgpu-best-practices/blob/main/img-textures.md#creating-a-texture-from-an-image-url
-  // pub async fn from_url(device: &Device, queue: &Queue, url: &str, label: &str) -> Result<Self, JsValue> {
-  //   let mut request_init = RequestInit::new();
-
-  //   request_init.method("GET");
-  //   request_init.mode(RequestMode::Cors);
-
-  //   let url = "./happy.png";
-  //   let window = window().unwrap();
-
-  //   let request =  Request::new_with_str_and_init(&url, &request_init)
-  //     .map_err(|_| JsValue::from(String::from("Test")))?;
-  
-  //   let response_raw = JsFuture::from(window.fetch_with_request(&request)).await?;
-
-  //   assert!(response_raw.is_instance_of::<Response>());
-
-  //   let response: Response = response_raw.dyn_into()?; 
-  //   let blob_future = JsFuture::from(response.array_buffer()?); 
-  //   let blob = blob_future.await?;
-
-  //   assert!(blob.is_instance_of::<ArrayBuffer>());
-
-  //   let buf: ArrayBuffer = blob.dyn_into()?;
-  //   let uint = Uint8Array::new(&buf).to_vec();
-  //   // let b2 = web_sys::Blob::new_with_u8_array_sequence(&Uint8Array::new(&buf))?; 
-  //   // let bitmap =  ImageBitmap::from(&b2);
-
-  //   Ok(Self::from_image(device, queue, &uint, label))
-  // }
-  
-  // pub fn from_image(device: &Device, queue: &Queue, buf: &[u8], label: &str) -> Self {
-  //   let width = bitmap.width();
-  //   let height = bitmap.height(); 
-  //   let size = Extent3d { width, height, depth_or_array_layers: 1 };
-  //   let texture = device.create_texture(&TextureDescriptor {
-  //     label: Some(label),
-  //     // TEXTURE_BINDING tells wgpu that we want to use this texture in shaders
-  //     // COPY_DST means that we want to copy data to this texture
-  //     usage: TextureUsages::TEXTURE_BINDING | TextureUsages::COPY_DST,
-  //     size,
-  //     dimension: TextureDimension::D2,
-  //     format: TextureFormat::Rgba8UnormSrgb, 
-  //     mip_level_count: 1,
-  //     sample_count: 1,
-  //   });
-
-  //   let image_copy_texture = ImageCopyTexture {
-  //       texture: &texture,
-  //       mip_level: 0,
-  //       origin: Origin3d::ZERO,
-  //       aspect: TextureAspect::All
-  //   }; 
-
-  //   let layout = ImageDataLayout {
-  //     offset: 0,
-  //     bytes_per_row: num::NonZeroU32::new(4 * width),
-  //     rows_per_image: num::NonZeroU32::new(height), 
-  //   };
-
-  //   // queue.copy_external_image_to_texture()
-
-  //   let view = texture.create_view(&TextureViewDescriptor::default());
-  //   let sampler = device.create_sampler(&SamplerDescriptor {
-  //     label: Some(label), 
-  //     // TEXTURE_BINDING tells wgpu that we want to use this texture in shaders
-  //     // COPY_DST means that we want to copy data to this texture
-  //     address_mode_u: AddressMode::ClampToEdge,
-  //     address_mode_v: AddressMode::ClampToEdge,
-  //     address_mode_w: AddressMode::ClampToEdge,
-  //     // The mag_filter and min_filter options describe what to do when a fragment covers multiple pixels,
-  //     // or there are multiple fragments for a single pixel
-  //     mag_filter: FilterMode::Linear,
-  //     min_filter: FilterMode::Nearest,
-  //     ..Default::default()
-  //   });
-
-  //   TextureResource { texture, view, sampler }
-  // }
+  // The caches are only borrowed once the bytes are in, so other loads can use them meanwhile
+  pub async fn from_url(device: &Device, queue: &Queue, samplers: &RefCell<SamplerCache>, mipmaps: &RefCell<MipmapGenerator>, url: &str, label: &str, options: &TextureOptions) -> Result<Self, TextureError> {
+    let bytes = resources::load_bytes(url).await?;
+
+    Self::from_bytes(device, queue, &mut samplers.borrow_mut(), &mut mipmaps.borrow_mut(), &bytes, label, options)
+  }
 }
 
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const PNG_SIGNATURE: &[u8] = &[0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+  const JPEG_SOI: &[u8] = &[0xFF, 0xD8, 0xFF, 0xE0];
+
+  #[test]
+  fn decodes_png() {
+    let img = decode(include_bytes!("happy.png")).expect("Decode happy.png");
+
+    assert!(img.width() > 0 && img.height() > 0);
+  }
+
+  #[test]
+  fn corrupt_png_is_decode_error() {
+    let bytes = [PNG_SIGNATURE, b"definitely not chunk data"].concat();
+
+    assert!(matches!(decode(&bytes), Err(TextureError::Decode(_))));
+  }
+
+  #[test]
+  fn truncated_png_is_decode_error() {
+    let bytes = include_bytes!("happy.png");
+
+    assert!(matches!(decode(&bytes[..bytes.len() / 2]), Err(TextureError::Decode(_))));
+  }
+
+  #[test]
+  fn corrupt_jpeg_is_decode_error() {
+    let bytes = [JPEG_SOI, b"definitely not a jfif segment"].concat();
+
+    assert!(matches!(decode(&bytes), Err(TextureError::Decode(_))));
+  }
+
+  #[test]
+  fn unknown_bytes_are_unsupported() {
+    assert!(matches!(decode(b"not an image"), Err(TextureError::UnsupportedFormat(_))));
+  }
+
+  #[test]
+  fn disabled_format_is_unsupported() {
+    assert!(matches!(decode(b"GIF89a\x01\x00\x01\x00"), Err(TextureError::UnsupportedFormat(_))));
+  }
+
+  #[test]
+  fn size_is_checked_against_limits() {
+    let limits = Limits::downlevel_webgl2_defaults();
+    let max = limits.max_texture_dimension_2d;
+
+    assert!(check_size(max, max, &limits).is_ok());
+    assert!(matches!(check_size(0, 1, &limits), Err(TextureError::Empty)));
+    assert!(matches!(check_size(max + 1, 1, &limits), Err(TextureError::TooLarge { .. })));
+  }
+}
@@ -0,0 +1,53 @@
+use cgmath::{Matrix4, One, Quaternion, Vector3};
+
+// Translation, rotation and scale, applied to points in scale, rotate, translate order
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Transform {
+  pub translation: Vector3<f32>,
+  pub rotation: Quaternion<f32>,
+  pub scale: Vector3<f32>,
+}
+
+impl Default for Transform {
+  fn default() -> Self {
+    Self {
+      translation: Vector3::new(0., 0., 0.),
+      rotation: Quaternion::one(),
+      scale: Vector3::new(1., 1., 1.),
+    }
+  }
+}
+
+impl Transform {
+  pub fn matrix(&self) -> Matrix4<f32> {
+    Matrix4::from_translation(self.translation)
+      * Matrix4::from(self.rotation)
+      * Matrix4::from_nonuniform_scale(self.scale.x, self.scale.y, self.scale.z)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use cgmath::{Deg, Point3, Rotation3, Transform as _};
+
+  #[test]
+  fn default_is_identity() {
+    assert_eq!(Transform::default().matrix(), Matrix4::one());
+  }
+
+  #[test]
+  fn applies_scale_then_rotation_then_translation() {
+    let transform = Transform {
+      translation: Vector3::new(1., 0., 0.),
+      rotation: Quaternion::from_angle_z(Deg(90.)),
+      scale: Vector3::new(2., 2., 2.),
+    };
+    // (1, 0, 0) scales to (2, 0, 0), rotates to (0, 2, 0) and then moves to (1, 2, 0)
+    let point = transform.matrix().transform_point(Point3::new(1., 0., 0.));
+
+    assert!((point.x - 1.).abs() < 1e-6);
+    assert!((point.y - 2.).abs() < 1e-6);
+    assert!(point.z.abs() < 1e-6);
+  }
+}
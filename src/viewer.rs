@@ -0,0 +1,285 @@
+use std::cell::RefCell;
+use std::fmt::Display;
+use std::rc::Rc;
+use cgmath::{InnerSpace, Point3, Quaternion, Vector3};
+use js_sys::{Array, Float32Array, Promise};
+use wasm_bindgen::JsCast;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen_futures::future_to_promise;
+use wgpu::Color;
+use winit::event_loop::{ControlFlow, EventLoop};
+
+use crate::canvas::Mount;
+use crate::config::{RendererConfig, RendererConfigInit};
+use crate::context::{CapabilityReportObject, RendererError};
+use crate::instance::InstanceData;
+use crate::scene::NodeId;
+use crate::transform::Transform;
+use crate::{init_logger, init_window, State, View};
+
+// The doc comments in this file end up in the generated TypeScript definitions
+
+#[wasm_bindgen(typescript_custom_section)]
+const TS_TYPES: &'static str = r#"
+export type Vec3 = [number, number, number] | Float32Array;
+export type Quat = [number, number, number, number] | Float32Array;
+"#;
+
+#[wasm_bindgen]
+extern "C" {
+  #[wasm_bindgen(typescript_type = "string | HTMLCanvasElement | undefined")]
+  pub type CanvasTarget;
+
+  #[wasm_bindgen(typescript_type = "Vec3")]
+  pub type Vec3;
+
+  #[wasm_bindgen(typescript_type = "Quat")]
+  pub type Quat;
+}
+
+// Shared with the event loop, which stops once the view has been taken out by destroy
+type SharedView = Rc<RefCell<Option<View>>>;
+
+/// A renderer drawing into a canvas on the page. Any number of viewers can run side by side,
+/// sharing a single GPU device.
+#[wasm_bindgen]
+pub struct Viewer {
+  view: SharedView,
+}
+
+#[wasm_bindgen]
+impl Viewer {
+  /// Starts rendering into a canvas, given as the element or its id. The canvas keeps the
+  /// size its CSS gives it. Without one, the viewer appends a canvas of the configured size to
+  /// the page body. If no adapter is found, the error thrown carries a `capabilities` report.
+  pub async fn create(canvas: CanvasTarget, config: RendererConfigInit) -> Result<Viewer, JsValue> {
+    let config = RendererConfig::from_js(&config)?;
+    let canvas = JsValue::from(canvas);
+    let mount = if canvas.is_undefined() || canvas.is_null() { Mount::Body } else { Mount::from(canvas) };
+
+    init_logger(config.log_level);
+
+    let event_loop = EventLoop::new();
+    let (window, resizer) = init_window(&event_loop, mount, &config).map_err(RendererError::Window)?;
+    let state = State::new(&window, &config).await?;
+    let view = Rc::new(RefCell::new(Some(View { window, state, resizer })));
+
+    run_event_loop(event_loop, view.clone())?;
+
+    Ok(Viewer { view })
+  }
+
+  /// Adds an .obj, .gltf or .glb model to the scene. Resolves to the ids of the nodes it was
+  /// added as, e.g. to move it with `setTransform`.
+  #[wasm_bindgen(js_name = loadModel)]
+  pub fn load_model(&self, url: String) -> Promise {
+    let view = self.view.clone();
+
+    future_to_promise(async move {
+      // Loading takes a while, the view keeps rendering in the meantime
+      let loader = with_state(&view, |state| state.loader())?;
+      let loaded = loader.load_model(&url).await.map_err(to_js_error)?;
+
+      let nodes = with_state(&view, |state| state.add_model(&url, loaded))?;
+
+      Ok(nodes.iter().map(|node| JsValue::from(node.index() as u32)).collect::<Array>().into())
+    })
+  }
+
+  /// Id of the node drawing the default quad.
+  #[wasm_bindgen(js_name = quadNode)]
+  pub fn quad_node(&self) -> Result<u32, JsValue> {
+    with_state(&self.view, |state| state.quad_node.index() as u32)
+  }
+
+  /// Places a node relative to its parent. `rotation` is a quaternion in x, y, z, w order, as
+  /// in glTF.
+  #[wasm_bindgen(js_name = setTransform)]
+  pub fn set_transform(&self, node: u32, translation: Vec3, rotation: Quat, scale: Vec3) -> Result<(), JsValue> {
+    let [x, y, z] = to_floats(&translation)?;
+    let [i, j, k, w] = to_floats(&rotation)?;
+    let [sx, sy, sz] = to_floats(&scale)?;
+    let transform = Transform {
+      translation: Vector3::new(x, y, z),
+      rotation: Quaternion::new(w, i, j, k),
+      scale: Vector3::new(sx, sy, sz),
+    };
+
+    with_state(&self.view, |state| {
+      let node = node_id(state, node)?;
+
+      state.scene.set_transform(node, transform);
+      Ok(())
+    })?
+  }
+
+  /// Moves a node, along with its children, under `parent`, or to the root without one. Returns
+  /// false, leaving the node where it is, if `parent` is the node itself or one of its
+  /// descendants.
+  #[wasm_bindgen(js_name = setParent)]
+  pub fn set_parent(&self, node: u32, parent: Option<u32>) -> Result<bool, JsValue> {
+    with_state(&self.view, |state| {
+      let node = node_id(state, node)?;
+      let parent = parent.map(|parent| node_id(state, parent)).transpose()?;
+
+      Ok(state.scene.set_parent(node, parent))
+    })?
+  }
+
+  /// Draws a node's model once per instance, all in a single draw call. `matrices` holds a
+  /// column-major 4x4 matrix per instance, relative to the node, and `colors` an optional rgba
+  /// color per instance that the material color is multiplied with. Fails for nodes without a
+  /// model.
+  #[wasm_bindgen(js_name = setInstances)]
+  pub fn set_instances(&self, node: u32, matrices: &[f32], colors: Option<Box<[f32]>>) -> Result<(), JsValue> {
+    let instances = to_instances(matrices, colors.as_deref())?;
+
+    with_state(&self.view, |state| {
+      let node = node_id(state, node)?;
+
+      if state.set_instances(node, &instances) {
+        Ok(())
+      } else {
+        Err(to_js_error(format!("Node {} has no model to instance", node.index())))
+      }
+    })?
+  }
+
+  /// Replaces the texture of the default quad.
+  #[wasm_bindgen(js_name = loadTexture)]
+  pub fn load_texture(&self, url: String) -> Promise {
+    let view = self.view.clone();
+
+    future_to_promise(async move {
+      let loader = with_state(&view, |state| state.loader())?;
+      let texture = loader.load_texture(&url).await.map_err(to_js_error)?;
+
+      with_state(&view, |state| state.set_quad_texture(texture))?;
+
+      Ok(JsValue::UNDEFINED)
+    })
+  }
+
+  /// Places the camera at `eye`, looking at `target`. The two must differ, and the camera can't
+  /// look straight up or down.
+  #[wasm_bindgen(js_name = setCameraPose)]
+  pub fn set_camera_pose(&self, eye: Vec3, target: Vec3) -> Result<(), JsValue> {
+    let eye = to_point(eye)?;
+    let target = to_point(target)?;
+
+    with_state(&self.view, |state| {
+      // Either would leave the view matrix without a defined orientation
+      if (target - eye).cross(state.camera.up).magnitude2() <= f32::EPSILON * (target - eye).magnitude2() {
+        return Err(to_js_error("The camera must look at a target other than its eye, and not straight up or down"));
+      }
+
+      state.set_camera_pose(eye, target);
+      Ok(())
+    })?
+  }
+
+  /// Sets the background, components are in [0, 1].
+  #[wasm_bindgen(js_name = setClearColor)]
+  pub fn set_clear_color(&self, r: f64, g: f64, b: f64, a: f64) -> Result<(), JsValue> {
+    with_state(&self.view, |state| state.clear_color = Color { r, g, b, a })
+  }
+
+  /// What the browser supports and which adapters were tried before one was found.
+  pub fn capabilities(&self) -> Result<CapabilityReportObject, JsValue> {
+    with_state(&self.view, |state| state.context.as_ref().map(|context| context.report.to_js()))?
+      .ok_or_else(|| to_js_error("Viewer has no surface"))
+  }
+
+  /// Stops rendering and releases the viewer's GPU resources. A canvas the viewer created is
+  /// removed from the page, one passed to `create` belongs to the page and stays, so it can be
+  /// given to another viewer. Any other call afterwards fails.
+  pub fn destroy(&self) {
+    // The event loop exits on its next event, once it finds the view gone
+    self.view.borrow_mut().take();
+  }
+}
+
+// winit's run never returns, on the web it throws to unwind out of Rust instead. Starting it
+// from a timeout keeps that exception from rejecting the create promise
+fn run_event_loop(event_loop: EventLoop<()>, view: SharedView) -> Result<(), JsValue> {
+  let run = Closure::once_into_js::<_, _, ()>(move || {
+    event_loop.run(move |event, _, control_flow| match view.borrow_mut().as_mut() {
+      Some(view) => view.handle_event(event, control_flow),
+      None => *control_flow = ControlFlow::Exit,
+    });
+  });
+
+  web_sys::window()
+    .ok_or("No window found")?
+    .set_timeout_with_callback(run.unchecked_ref())?;
+
+  Ok(())
+}
+
+fn with_state<T>(view: &SharedView, f: impl FnOnce(&mut State) -> T) -> Result<T, JsValue> {
+  match view.borrow_mut().as_mut() {
+    Some(view) => Ok(f(&mut view.state)),
+    None => Err(to_js_error("Viewer has been destroyed")),
+  }
+}
+
+fn node_id(state: &State, node: u32) -> Result<NodeId, JsValue> {
+  state.scene.node_id(node as usize).ok_or_else(|| to_js_error(format!("No node {}", node)))
+}
+
+// Takes a plain array of numbers or a Float32Array
+fn to_floats<const N: usize>(value: &JsValue) -> Result<[f32; N], JsValue> {
+  let error = || to_js_error(format!("Expected an array or Float32Array of {} finite numbers", N));
+  let values: Vec<f32> = if let Some(array) = value.dyn_ref::<Float32Array>() {
+    array.to_vec()
+  } else if Array::is_array(value) {
+    Array::from(value).iter()
+      .map(|value| value.as_f64().map(|value| value as f32))
+      .collect::<Option<_>>()
+      .ok_or_else(error)?
+  } else {
+    return Err(error());
+  };
+
+  if !values.iter().all(|value| value.is_finite()) {
+    return Err(error());
+  }
+
+  values.try_into().map_err(|_| error())
+}
+
+fn to_point(value: Vec3) -> Result<Point3<f32>, JsValue> {
+  let [x, y, z] = to_floats(&value)?;
+
+  Ok(Point3::new(x, y, z))
+}
+
+fn to_instances(matrices: &[f32], colors: Option<&[f32]>) -> Result<Vec<InstanceData>, JsValue> {
+  let count = matrices.len() / 16;
+
+  if !matrices.len().is_multiple_of(16) {
+    return Err(to_js_error("Expected 16 numbers per instance matrix"));
+  }
+
+  if colors.is_some_and(|colors| colors.len() != count * 4) {
+    return Err(to_js_error(format!("Expected 4 color components for each of the {} instances", count)));
+  }
+
+  Ok((0..count).map(|i| {
+    let m = &matrices[i * 16..(i + 1) * 16];
+
+    InstanceData {
+      model_mat: [
+        [m[0], m[1], m[2], m[3]],
+        [m[4], m[5], m[6], m[7]],
+        [m[8], m[9], m[10], m[11]],
+        [m[12], m[13], m[14], m[15]],
+      ],
+      color: colors.map_or([1.; 4], |colors| [colors[i * 4], colors[i * 4 + 1], colors[i * 4 + 2], colors[i * 4 + 3]]),
+    }
+  }).collect())
+}
+
+fn to_js_error(e: impl Display) -> JsValue {
+  js_sys::Error::new(&e.to_string()).into()
+}
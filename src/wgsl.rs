@@ -0,0 +1,136 @@
+use std::borrow::Cow;
+
+// Our shaders use the current WGSL syntax understood by browsers, `@location(0)` attributes
+// and comma separated struct members. On native and with WebGL2, wgpu parses WGSL with naga 0.8
+// which still expects `[[location(0)]]` and semicolons, so the source is rewritten before it is
+// handed over
+#[cfg(all(target_arch = "wasm32", not(feature = "webgl")))]
+pub fn source(wgsl: &str) -> Cow<'_, str> {
+  Cow::Borrowed(wgsl)
+}
+
+#[cfg(any(not(target_arch = "wasm32"), feature = "webgl"))]
+pub fn source(wgsl: &str) -> Cow<'_, str> {
+  Cow::Owned(to_legacy_syntax(wgsl))
+}
+
+#[cfg(any(not(target_arch = "wasm32"), feature = "webgl"))]
+fn to_legacy_syntax(wgsl: &str) -> String {
+  let mut out = String::with_capacity(wgsl.len());
+  let mut in_struct = false;
+
+  for line in wgsl.lines() {
+    let trimmed = line.trim();
+    let mut line = convert_attributes(line);
+
+    if trimmed.starts_with("struct ") {
+      in_struct = true;
+    } else if trimmed.starts_with('}') {
+      in_struct = false;
+    } else if in_struct && !trimmed.starts_with("//") {
+      if let Some(member) = line.trim_end().strip_suffix(',') {
+        line = format!("{};", member);
+      }
+    }
+
+    out.push_str(&line);
+    out.push('\n');
+  }
+
+  // Attributes written on their own lines, e.g. `@group(0)` followed by `@binding(0)`, were
+  // each wrapped separately, merge them into a single list
+  merge_adjacent_lists(&out)
+}
+
+// Wraps each `@name` or `@name(args)` in the line as `[[name(args)]]`
+#[cfg(any(not(target_arch = "wasm32"), feature = "webgl"))]
+fn convert_attributes(line: &str) -> String {
+  let mut out = String::with_capacity(line.len() + 8);
+  let mut chars = line.char_indices().peekable();
+
+  while let Some((i, c)) = chars.next() {
+    if c == '/' && line[i..].starts_with("//") {
+      out.push_str(&line[i..]);
+      break;
+    }
+
+    if c != '@' {
+      out.push(c);
+      continue;
+    }
+
+    let mut attribute = String::new();
+    let mut depth = 0;
+
+    while let Some(&(_, c)) = chars.peek() {
+      match c {
+        '(' => depth += 1,
+        ')' => depth -= 1,
+        c if depth == 0 && !(c.is_alphanumeric() || c == '_') => break,
+        _ => {}
+      }
+
+      attribute.push(c);
+      chars.next();
+
+      if depth == 0 && c == ')' {
+        break;
+      }
+    }
+
+    out.push_str(&format!("[[{}]]", attribute));
+  }
+
+  out
+}
+
+#[cfg(any(not(target_arch = "wasm32"), feature = "webgl"))]
+fn merge_adjacent_lists(wgsl: &str) -> String {
+  let mut out = String::with_capacity(wgsl.len());
+  let mut rest = wgsl;
+
+  while let Some(end) = rest.find("]]") {
+    out.push_str(&rest[..end]);
+    rest = &rest[end + 2..];
+
+    match rest.trim_start().strip_prefix("[[") {
+      Some(next) => {
+        out.push_str(", ");
+        rest = next;
+      }
+      None => out.push_str("]]"),
+    }
+  }
+
+  out.push_str(rest);
+  out
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn converts_attributes() {
+    assert_eq!(
+      to_legacy_syntax("@stage(vertex)\nfn vs_main(@builtin(vertex_index) index: u32) -> @location(0) vec4<f32> {"),
+      "[[stage(vertex)]]\nfn vs_main([[builtin(vertex_index)]] index: u32) -> [[location(0)]] vec4<f32> {\n"
+    );
+  }
+
+  #[test]
+  fn merges_attributes_on_separate_lines() {
+    assert_eq!(
+      to_legacy_syntax("@group(0)\n@binding(1)\nvar diffuse_sampler: sampler;"),
+      "[[group(0), binding(1)]]\nvar diffuse_sampler: sampler;\n"
+    );
+  }
+
+  #[test]
+  fn terminates_struct_members_with_semicolons() {
+    assert_eq!(
+      to_legacy_syntax("struct Output {\n  @location(0) color: vec3<f32>, \n  // a, b\n  uv: vec2<f32>,\n};\nlet v = vec2<f32>(1.,\n 2.);"),
+      "struct Output {\n  [[location(0)]] color: vec3<f32>;\n  // a, b\n  uv: vec2<f32>;\n};\nlet v = vec2<f32>(1.,\n 2.);\n"
+    );
+  }
+}
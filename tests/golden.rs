@@ -0,0 +1,225 @@
+#![cfg(not(target_arch = "wasm32"))]
+
+// Renders fixed scenes headlessly and compares them against the reference images in
+// tests/golden. Run with WEXP_BLESS=1 to (re)write the references after an intended change.
+// On failure the actual image and a diff image are written next to the test's target dir.
+// Rendering needs an adapter, so those tests are ignored by default and run with
+// `cargo test --test golden -- --ignored`
+//
+// The references were rendered on the software fallback adapter headless rendering prefers,
+// llvmpipe (Mesa 22.3.6) through wgpu's GL backend, and pass at TOLERANCE
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use image::{Rgba, RgbaImage};
+use wexp::config::RendererConfig;
+use wexp::camera::DepthMode;
+use wexp::headless::{self, HeadlessRenderer};
+use wexp::instance::InstanceData;
+use wgpu::Color;
+use cgmath::{Matrix4, Vector3};
+
+// Allowed difference per channel, absorbs rounding differences between software rasterizers
+const TOLERANCE: u8 = 2;
+
+fn reference_dir() -> PathBuf {
+  Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("golden")
+}
+
+fn output_dir() -> PathBuf {
+  Path::new(env!("CARGO_TARGET_TMPDIR")).join("golden")
+}
+
+// Mismatched pixels are drawn red on top of a faded copy of the expected image
+fn diff_images(expected: &RgbaImage, actual: &RgbaImage, tolerance: u8) -> (RgbaImage, usize) {
+  let mut mismatched = 0;
+  let diff = RgbaImage::from_fn(expected.width(), expected.height(), |x, y| {
+    let a = expected.get_pixel(x, y);
+    let b = actual.get_pixel(x, y);
+    let matches = a.0.iter().zip(b.0.iter()).all(|(a, b)| a.abs_diff(*b) <= tolerance);
+
+    if matches {
+      let luma = (a[0] as u32 + a[1] as u32 + a[2] as u32) / 3;
+      let faded = (luma / 4 + 192) as u8;
+
+      Rgba([faded, faded, faded, 255])
+    } else {
+      mismatched += 1;
+      Rgba([255, 0, 0, 255])
+    }
+  });
+
+  (diff, mismatched)
+}
+
+fn check_golden(name: &str, actual: &RgbaImage) {
+  let reference_path = reference_dir().join(format!("{}.png", name));
+
+  if env::var_os("WEXP_BLESS").is_some() {
+    fs::create_dir_all(reference_dir()).expect("Create reference dir");
+    actual.save(&reference_path).expect("Write reference image");
+    return;
+  }
+
+  fs::create_dir_all(output_dir()).expect("Create output dir");
+
+  let actual_path = output_dir().join(format!("{}.actual.png", name));
+  let expected = match image::open(&reference_path) {
+    Ok(expected) => expected.to_rgba8(),
+    Err(e) => {
+      actual.save(&actual_path).expect("Write actual image");
+      panic!("Unable to open {}: {}. Rendered {}, run with WEXP_BLESS=1 to accept it",
+        reference_path.display(), e, actual_path.display());
+    }
+  };
+
+  assert_eq!(expected.dimensions(), actual.dimensions(), "{} changed size", name);
+
+  let (diff, mismatched) = diff_images(&expected, actual, TOLERANCE);
+
+  if mismatched > 0 {
+    let diff_path = output_dir().join(format!("{}.diff.png", name));
+
+    actual.save(&actual_path).expect("Write actual image");
+    diff.save(&diff_path).expect("Write diff image");
+
+    panic!("{}: {} pixels differ by more than {}, see {} and {}",
+      name, mismatched, TOLERANCE, actual_path.display(), diff_path.display());
+  }
+}
+
+// Fails rather than skips without an adapter, the golden tests are opt in
+fn render(config: RendererConfig) -> RgbaImage {
+  headless::render_to_image_with_config(&config).unwrap_or_else(|e| panic!("Failed to render: {}", e))
+}
+
+fn sized(width: u32, height: u32) -> RendererConfig {
+  RendererConfig { width, height, ..Default::default() }
+}
+
+fn renderer() -> HeadlessRenderer {
+  HeadlessRenderer::new(&sized(256, 256)).unwrap_or_else(|e| panic!("Failed to create renderer: {}", e))
+}
+
+fn fixture(name: &str) -> String {
+  format!("{}/tests/fixtures/{}", env!("CARGO_MANIFEST_DIR"), name)
+}
+
+#[test]
+#[ignore = "needs an adapter, run with --ignored"]
+fn textured_quad() {
+  check_golden("textured_quad", &render(sized(256, 256)));
+}
+
+// The camera's aspect follows the target, so the quad keeps its shape
+#[test]
+#[ignore = "needs an adapter, run with --ignored"]
+fn textured_quad_wide() {
+  check_golden("textured_quad_wide", &render(sized(512, 256)));
+}
+
+#[test]
+#[ignore = "needs an adapter, run with --ignored"]
+fn textured_quad_small() {
+  check_golden("textured_quad_small", &render(sized(64, 48)));
+}
+
+#[test]
+#[ignore = "needs an adapter, run with --ignored"]
+fn clear_color() {
+  let config = RendererConfig { clear_color: Color { r: 0.1, g: 0.2, b: 0.3, a: 1. }, ..sized(256, 256) };
+
+  check_golden("clear_color", &render(config));
+}
+
+// Reverse-z only changes how depth is stored, so it must match the standard depth reference
+#[test]
+#[ignore = "needs an adapter, run with --ignored"]
+fn reverse_z_matches_standard() {
+  let config = RendererConfig { depth_mode: DepthMode::ReverseZ, ..sized(256, 256) };
+
+  if env::var_os("WEXP_BLESS").is_none() {
+    check_golden("textured_quad", &render(config));
+  }
+}
+
+// A 3x3 grid of tinted copies of the quad, drawn in one call
+#[test]
+#[ignore = "needs an adapter, run with --ignored"]
+fn instanced_grid() {
+  let mut renderer = renderer();
+  let quad = renderer.quad();
+  let instances: Vec<InstanceData> = (0..9).map(|i| {
+    let (x, y) = ((i % 3) as f32 - 1., (i / 3) as f32 - 1.);
+    let model_mat = Matrix4::from_translation(Vector3::new(x * 0.6, y * 0.6, 0.)) * Matrix4::from_scale(0.25);
+
+    InstanceData::new(model_mat, [x.abs(), y.abs(), 1., 1.])
+  }).collect();
+
+  assert!(renderer.set_instances(quad, &instances));
+  check_golden("instanced_grid", &renderer.render().unwrap());
+}
+
+#[test]
+#[ignore = "needs an adapter, run with --ignored"]
+fn obj_model() {
+  let mut renderer = renderer();
+  let nodes = renderer.load_model(&fixture("triangle.obj")).unwrap();
+
+  renderer.transform_mut(nodes[0]).translation.z = 0.25;
+  check_golden("obj_model", &renderer.render().unwrap());
+}
+
+#[test]
+#[ignore = "needs an adapter, run with --ignored"]
+fn gltf_model() {
+  let mut renderer = renderer();
+
+  renderer.load_model(&fixture("triangle.gltf")).unwrap();
+  check_golden("gltf_model", &renderer.render().unwrap());
+}
+
+// The quad is parented to the triangle, so it shrinks along with it and its offset to the left
+// is halved, which puts it in the middle with the triangle to its right
+#[test]
+#[ignore = "needs an adapter, run with --ignored"]
+fn hierarchy() {
+  let mut renderer = renderer();
+  let quad = renderer.quad();
+  let triangle = renderer.load_model(&fixture("triangle.obj")).unwrap()[0];
+
+  assert!(renderer.set_parent(quad, Some(triangle)));
+  assert!(!renderer.set_parent(triangle, Some(quad)));
+
+  renderer.transform_mut(quad).translation = Vector3::new(-1., 0., 0.);
+
+  let transform = renderer.transform_mut(triangle);
+
+  transform.translation = Vector3::new(0.5, 0., 0.);
+  transform.scale = Vector3::new(0.5, 0.5, 0.5);
+
+  check_golden("hierarchy", &renderer.render().unwrap());
+}
+
+#[test]
+fn diff_allows_tolerance() {
+  let expected = RgbaImage::from_pixel(4, 4, Rgba([100, 100, 100, 255]));
+  let actual = RgbaImage::from_pixel(4, 4, Rgba([100 + TOLERANCE, 100 - TOLERANCE, 100, 255]));
+
+  assert_eq!(diff_images(&expected, &actual, TOLERANCE).1, 0);
+}
+
+#[test]
+fn diff_marks_mismatched_pixels() {
+  let expected = RgbaImage::from_pixel(4, 4, Rgba([100, 100, 100, 255]));
+  let mut actual = expected.clone();
+
+  actual.put_pixel(1, 2, Rgba([100, 100, 100 + TOLERANCE + 1, 255]));
+
+  let (diff, mismatched) = diff_images(&expected, &actual, TOLERANCE);
+
+  assert_eq!(mismatched, 1);
+  assert_eq!(*diff.get_pixel(1, 2), Rgba([255, 0, 0, 255]));
+  assert_ne!(*diff.get_pixel(0, 0), Rgba([255, 0, 0, 255]));
+}
@@ -0,0 +1,26 @@
+#![cfg(not(target_arch = "wasm32"))]
+
+// Parses and validates the shaders the way wgpu does on native, which catches syntax the
+// bundled naga doesn't understand without needing an adapter
+
+use naga::valid::{Capabilities, ValidationFlags, Validator};
+
+fn validate(name: &str, source: &str) {
+  let source = wexp::wgsl::source(source);
+  let module = naga::front::wgsl::parse_str(&source)
+    .unwrap_or_else(|e| panic!("Failed to parse {}: {}", name, e.emit_to_string(&source)));
+
+  Validator::new(ValidationFlags::all(), Capabilities::empty())
+    .validate(&module)
+    .unwrap_or_else(|e| panic!("Invalid {}: {:?}", name, e));
+}
+
+#[test]
+fn main_shader_is_valid() {
+  validate("shader.wgsl", include_str!("../src/shader.wgsl"));
+}
+
+#[test]
+fn blit_shader_is_valid() {
+  validate("blit.wgsl", include_str!("../src/blit.wgsl"));
+}